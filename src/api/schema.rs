@@ -1,5 +1,7 @@
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
+use schemars::JsonSchema;
 use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 /// rust-microservice-package-manager cli struct
 #[derive(Parser)]
@@ -17,8 +19,7 @@ pub struct Cli {
         short,
         long,
         value_name = "loglevel",
-        default_value = "info",
-        help = "Set the log level [possible values: info, debug, trace]"
+        help = "Set the log level [possible values: info, debug, trace] (default info)"
     )]
     pub loglevel: Option<String>,
 
@@ -39,6 +40,99 @@ pub struct Cli {
         help = "The server ip address for the worker to connect to (default 127.0.0.1)"
     )]
     pub server_ip: Option<String>,
+
+    /// controller endpoint (worker and cli commands)
+    #[arg(
+        long,
+        value_name = "controller",
+        help = "The controller endpoint [ws://|wss://]host[:port] (default ws://127.0.0.1:2000)"
+    )]
+    pub controller: Option<String>,
+
+    /// listen endpoint (only for controller)
+    #[arg(
+        long,
+        value_name = "listen",
+        help = "The address the controller listens on [ws://|wss://]host[:port] (default 127.0.0.1:2000)"
+    )]
+    pub listen: Option<String>,
+
+    /// settings file
+    #[arg(
+        long,
+        value_name = "config",
+        help = "The settings file (default ~/.config/mpm/config.yaml)"
+    )]
+    pub config: Option<String>,
+
+    /// directory holding the signing keys
+    #[arg(
+        long,
+        value_name = "key-dir",
+        help = "The directory holding private.pem and public.pem (default <data-dir>/.ssh)"
+    )]
+    pub key_dir: Option<String>,
+
+    /// trust policy evaluated by verify and stage
+    #[arg(
+        long,
+        value_name = "trust-policy",
+        help = "The trust policy file (default trust-policy.yaml in the key dir, public.pem is trusted without one)"
+    )]
+    pub trust_policy: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "tls-cert",
+        help = "The tls certificate (pem) used by the controller when listening on wss"
+    )]
+    pub tls_cert: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "tls-key",
+        help = "The tls private key (pkcs8 pem) used by the controller when listening on wss"
+    )]
+    pub tls_key: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "tls-ca",
+        help = "An additional ca certificate (pem) to trust when connecting to the controller over wss"
+    )]
+    pub tls_ca: Option<String>,
+
+    /// base directory for controller and worker state (join tokens, credentials)
+    #[arg(
+        long,
+        value_name = "data-dir",
+        help = "The base directory used to store controller and worker state (default .)"
+    )]
+    pub data_dir: Option<String>,
+
+    /// join token (only for worker)
+    #[arg(
+        long,
+        value_name = "join-token",
+        help = "The join token a worker presents to enroll with the controller (only needed on first join)"
+    )]
+    pub join_token: Option<String>,
+
+    /// api token sent with cli commands to the controller
+    #[arg(
+        long,
+        value_name = "api-token",
+        help = "The api token (controller token create --api) cli commands present to the controller"
+    )]
+    pub api_token: Option<String>,
+
+    /// http api listen address (only for controller)
+    #[arg(
+        long,
+        value_name = "api-listen",
+        help = "Serve the controller http api on this address e.g. 127.0.0.1:2080 (disabled by default, requests need an api token)"
+    )]
+    pub api_listen: Option<String>,
 }
 
 #[derive(Subcommand)]
@@ -66,13 +160,33 @@ pub enum Commands {
             help = "If set will skip tls-verify and use http for the remote registry"
         )]
         skip_tls_verify: bool,
-        //#[arg(
-        //    short,
-        //    long,
-        //    value_name = "node",
-        //    help = "Package artifacts on a specific node (must be a registered client)"
-        //)]
-        //node: String,
+        #[arg(
+            short,
+            long,
+            value_name = "node",
+            help = "Package artifacts on a specific node (must be a registered client), runs locally if not set"
+        )]
+        node: Option<String>,
+        #[arg(
+            short,
+            long,
+            value_name = "reproducible",
+            help = "Normalize layer entries and timestamps (uses SOURCE_DATE_EPOCH) so identical inputs produce identical digests"
+        )]
+        reproducible: bool,
+        #[arg(
+            long,
+            value_enum,
+            value_name = "compression",
+            help = "Layer compression (gzip, zstd or none), overrides the compression set in the config"
+        )]
+        compression: Option<LayerCompression>,
+        #[arg(
+            long,
+            value_name = "private-key",
+            help = "Private key path, env:<VAR> or fd:<N> to sign with (default private.pem in the key dir), with --node a path or env:<VAR> on the worker"
+        )]
+        private_key: Option<String>,
     },
     /// used to pull oci images from a registry and verify binaries are signed
     Stage {
@@ -111,6 +225,19 @@ pub enum Commands {
             help = "Deploy to a specific node (hostname of server) or all servers"
         )]
         node: String,
+        #[arg(
+            short,
+            long,
+            value_name = "update",
+            help = "Resolve tags again and update the digests pinned in microservices.lock"
+        )]
+        update: bool,
+        #[arg(
+            long,
+            value_name = "insecure-allow-unsigned",
+            help = "Install services that have no signature (tampered signatures are always refused)"
+        )]
+        insecure_allow_unsigned: bool,
     },
     /// CreateReferralManifest subcommand (to build signed artifact manifests)
     CreateReferralManifest {
@@ -137,9 +264,27 @@ pub enum Commands {
             help = "The image format oci or dockerv2 (required)"
         )]
         format: String,
+        #[arg(
+            short,
+            long,
+            value_name = "working-dir",
+            default_value = ".",
+            help = "The base working directory holding generated artifacts and signatures"
+        )]
+        working_dir: String,
     },
     /// Keypair (create PEM keypair)
-    Keypair {},
+    Keypair {
+        #[arg(
+            short,
+            long,
+            value_enum,
+            value_name = "algorithm",
+            default_value_t = KeyAlgorithm::Rsa,
+            help = "Key algorithm (rsa, ed25519 or ecdsa-p256)"
+        )]
+        algorithm: KeyAlgorithm,
+    },
     /// Sign a binary artifact
     Sign {
         #[arg(
@@ -149,6 +294,20 @@ pub enum Commands {
             help = "The artfifact to sign (required)"
         )]
         artifact: String,
+        #[arg(
+            long,
+            value_name = "private-key",
+            help = "Private key path, env:<VAR> or fd:<N> (default private.pem in the key dir)"
+        )]
+        private_key: Option<String>,
+        #[arg(
+            short,
+            long,
+            value_name = "working-dir",
+            default_value = ".",
+            help = "The base working directory, the signature is written to its signatures folder"
+        )]
+        working_dir: String,
     },
     /// Verify the binary artifact (if signed will return true)
     Verify {
@@ -159,6 +318,26 @@ pub enum Commands {
             help = "The artfifact to verify (required)"
         )]
         artifact: String,
+        #[arg(
+            long,
+            value_name = "public-key",
+            help = "Public key path, env:<VAR> or fd:<N> (trusted instead of the trust policy)"
+        )]
+        public_key: Option<String>,
+        #[arg(
+            long,
+            value_name = "registry",
+            help = "Registry reference of the artifact, matched against the registry of the trust policy rules"
+        )]
+        registry: Option<String>,
+        #[arg(
+            short,
+            long,
+            value_name = "working-dir",
+            default_value = ".",
+            help = "The base working directory, the signature is read from its signatures folder"
+        )]
+        working_dir: String,
     },
     /// Start a specific microservice
     Start {
@@ -263,96 +442,222 @@ pub enum Commands {
         #[arg(short, long, value_name = "subnet", help = "Bridge subnet (required)")]
         subnet: u8,
     },
+    /// Export services (images and signature referrers) from the registry to an oci layout archive
+    Export {
+        #[arg(
+            short,
+            long,
+            value_name = "config-file",
+            help = "The config file listing the services to export (required)"
+        )]
+        config_file: String,
+        #[arg(
+            short,
+            long,
+            value_name = "working-dir",
+            help = "The base working directory used to assemble the oci layout (required)"
+        )]
+        working_dir: String,
+        #[arg(
+            short,
+            long,
+            value_name = "output",
+            help = "The oci layout archive (tar) to create (required)"
+        )]
+        output: String,
+        #[arg(
+            long,
+            value_name = "service",
+            help = "Only export this service (default all services in the config)"
+        )]
+        service: Option<String>,
+        #[arg(
+            short,
+            long,
+            value_name = "skip-tls-verify",
+            help = "If set will skip tls-verify and use http for the remote registry"
+        )]
+        skip_tls_verify: bool,
+    },
+    /// Import services (images and signature referrers) from an oci layout archive into the registry
+    Import {
+        #[arg(
+            short,
+            long,
+            value_name = "config-file",
+            help = "The config file with the registry of each service to import (required)"
+        )]
+        config_file: String,
+        #[arg(
+            short,
+            long,
+            value_name = "working-dir",
+            help = "The base working directory used to unpack the oci layout (required)"
+        )]
+        working_dir: String,
+        #[arg(
+            short,
+            long,
+            value_name = "input",
+            help = "The oci layout archive (tar) to import (required)"
+        )]
+        input: String,
+        #[arg(
+            long,
+            value_name = "service",
+            help = "Only import this service (default all services in the config)"
+        )]
+        service: Option<String>,
+        #[arg(
+            short,
+            long,
+            value_name = "skip-tls-verify",
+            help = "If set will skip tls-verify and use http for the remote registry"
+        )]
+        skip_tls_verify: bool,
+    },
+    /// Stream controller events (nodes joining or leaving, service lifecycle, networks)
+    Events {
+        #[arg(
+            short,
+            long,
+            value_name = "follow",
+            help = "Keep the connection open and print new events as they happen"
+        )]
+        follow: bool,
+        #[arg(short, long, value_name = "json", help = "Print events as json lines")]
+        json: bool,
+    },
+    /// Long running jobs (stage and package) tracked by the controller
+    Jobs {
+        #[command(subcommand)]
+        command: JobsCommands,
+    },
+    /// Controller administration
+    Controller {
+        #[command(subcommand)]
+        command: ControllerCommands,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum JobsCommands {
+    /// List recent jobs
+    List {},
+    /// Print the progress of a job until it completes
+    Wait {
+        #[arg(value_name = "id", help = "The job id (required)")]
+        id: String,
+    },
+    /// Cancel a running job
+    Cancel {
+        #[arg(value_name = "id", help = "The job id (required)")]
+        id: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ControllerCommands {
+    /// Manage join tokens used to enroll workers and tokens for the http api
+    Token {
+        #[command(subcommand)]
+        command: TokenCommands,
+    },
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Subcommand)]
+pub enum TokenCommands {
+    /// Create a short-lived join token (run on the controller host)
+    Create {
+        #[arg(
+            short,
+            long,
+            value_name = "ttl",
+            default_value = "900",
+            help = "The token time to live in seconds (default 900)"
+        )]
+        ttl: u64,
+        #[arg(
+            long,
+            value_name = "api",
+            help = "Create a bearer token for the http api instead of a join token"
+        )]
+        api: bool,
+    },
+}
+
+/// oci image config (application/vnd.oci.image.config.v1+json)
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BaseConfig {
     #[serde(rename = "created")]
-    created: String,
+    pub created: String,
 
     #[serde(rename = "architecture")]
-    architecture: String,
+    pub architecture: String,
 
     #[serde(rename = "os")]
-    os: String,
+    pub os: String,
 
     #[serde(rename = "config")]
-    config: Config,
+    pub config: Config,
 
     #[serde(rename = "rootfs")]
-    rootfs: Rootfs,
+    pub rootfs: Rootfs,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
     #[serde(rename = "Env")]
-    env: Vec<String>,
+    pub env: Vec<String>,
+
+    #[serde(rename = "Entrypoint")]
+    pub entrypoint: Vec<String>,
 
     #[serde(rename = "Cmd")]
-    cmd: Vec<String>,
+    pub cmd: Vec<String>,
+
+    #[serde(rename = "WorkingDir")]
+    pub working_dir: String,
 
     #[serde(rename = "Labels")]
-    labels: Labels,
+    pub labels: Labels,
 }
 
-#[derive(Serialize, Deserialize)]
+/// image labels, using the pre-defined oci annotation keys
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Labels {
-    #[serde(rename = "architecture")]
-    architecture: String,
-
-    #[serde(rename = "build-date")]
-    build_date: String,
-
-    #[serde(rename = "component")]
-    component: String,
-
-    #[serde(rename = "description")]
-    description: String,
-
-    #[serde(rename = "distribution-scope")]
-    distribution_scope: String,
-
-    #[serde(rename = "maintainer")]
-    maintainer: String,
-
-    #[serde(rename = "name")]
-    name: String,
-
-    #[serde(rename = "release")]
-    release: String,
-
-    #[serde(rename = "summary")]
-    summary: String,
-
-    #[serde(rename = "url")]
-    url: String,
+    #[serde(rename = "org.opencontainers.image.title")]
+    pub title: String,
 
-    #[serde(rename = "vcs-ref")]
-    vcs_ref: String,
+    #[serde(rename = "org.opencontainers.image.version")]
+    pub version: String,
 
-    #[serde(rename = "vcs-type")]
-    vcs_type: String,
+    #[serde(rename = "org.opencontainers.image.description")]
+    pub description: String,
 
-    #[serde(rename = "vendor")]
-    vendor: String,
+    #[serde(rename = "org.opencontainers.image.authors")]
+    pub authors: String,
 
-    #[serde(rename = "version")]
-    version: String,
+    #[serde(rename = "org.opencontainers.image.created")]
+    pub created: String,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Rootfs {
     #[serde(rename = "type")]
-    rootfs_type: String,
+    pub rootfs_type: String,
 
+    /// sha256 digests of the uncompressed layers
     #[serde(rename = "diff_ids")]
-    diff_ids: Vec<String>,
+    pub diff_ids: Vec<String>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct OCIIndex {
     pub schema_version: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
     pub manifests: Vec<Layer>,
 }
 
@@ -364,6 +669,13 @@ pub struct Layer {
     pub digest: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub annotations: Option<Annotations>,
+    /// set on the entries of a multi-architecture image index
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub platform: Option<ManifestPlatform>,
+    /// set on the entries of a referrers index
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "artifactType")]
+    pub artifact_type: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -422,16 +734,77 @@ pub struct Annotations {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "org.opencontainers.image.created")]
     pub image_created: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "org.opencontainers.image.ref.name")]
+    pub ref_name: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct SignatureJson {
-    #[serde(rename = "artitact")]
+    /// signature blobs created before the key was fixed use artitact
+    #[serde(rename = "artifact", alias = "artitact")]
     pub artifact: String,
+    /// signatures created before the algorithm was recorded are rsa
+    #[serde(default)]
+    #[serde(rename = "algorithm")]
+    pub algorithm: KeyAlgorithm,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "payload")]
+    pub payload: Option<String>,
+    #[serde(rename = "signature")]
+    pub signature: String,
+}
+
+/// detached signature written by sign (the signed payload, base64 encoded signature and the key algorithm),
+/// signatures without a payload cover the raw file
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SignatureFile {
+    #[serde(rename = "algorithm")]
+    pub algorithm: KeyAlgorithm,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "payload")]
+    pub payload: Option<String>,
     #[serde(rename = "signature")]
     pub signature: String,
 }
 
+/// claims bound by a signature, the signature covers the serialized payload
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SignaturePayload {
+    #[serde(rename = "name")]
+    pub name: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "version")]
+    pub version: Option<String>,
+
+    /// digest of the platform manifest the binary is packaged in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "manifestDigest")]
+    pub manifest_digest: Option<String>,
+
+    #[serde(rename = "binaryDigest")]
+    pub binary_digest: String,
+
+    #[serde(rename = "created")]
+    pub created: String,
+
+    /// sha256 of the public key (der)
+    #[serde(rename = "keyId")]
+    pub key_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyAlgorithm {
+    #[default]
+    Rsa,
+    Ed25519,
+    EcdsaP256,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MicroserviceConfig {
     #[serde(rename = "apiVersion")]
@@ -457,7 +830,7 @@ pub struct Service {
     pub name: String,
 
     /// binary_path is the path to the actual microservice project on disk
-    /// and the link to the binary
+    /// and the link to the binary (relative to the config file)
     #[serde(rename = "binaryPath")]
     pub binary_path: String,
 
@@ -479,6 +852,151 @@ pub struct Service {
 
     #[serde(rename = "args")]
     pub args: Option<Vec<KeyValue>>,
+
+    /// per platform binaries, packaged as a multi-architecture image
+    /// (binaryPath is used for the host platform when not set)
+    #[serde(rename = "platforms")]
+    pub platforms: Option<Vec<Platform>>,
+
+    /// files packaged with the binary (config files, static assets)
+    #[serde(rename = "files")]
+    pub files: Option<Vec<PackageFile>>,
+
+    /// extra layer groups (e.g. config, assets), each group is packaged as its own layer
+    /// after the binary layer so a change to one group leaves the other layers untouched
+    #[serde(rename = "layers")]
+    pub layers: Option<Vec<LayerGroup>>,
+
+    /// layer compression (gzip, zstd or none), default gzip
+    #[serde(rename = "compression")]
+    pub compression: Option<LayerCompression>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LayerGroup {
+    #[serde(rename = "name")]
+    pub name: String,
+
+    #[serde(rename = "files")]
+    pub files: Vec<PackageFile>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PackageFile {
+    /// file, directory or glob pattern (relative to the config file)
+    #[serde(rename = "source")]
+    pub source: String,
+
+    /// path in the image (default /), a directory when it ends with /
+    /// or when the source matches several files
+    #[serde(rename = "destination")]
+    pub destination: Option<String>,
+
+    /// octal permissions e.g. "0644" (default is the mode of the source file)
+    #[serde(rename = "mode")]
+    pub mode: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Platform {
+    /// oci architecture name (amd64, arm64)
+    #[serde(rename = "architecture")]
+    pub architecture: String,
+
+    /// defaults to linux
+    #[serde(rename = "os")]
+    pub os: Option<String>,
+
+    #[serde(rename = "binaryPath")]
+    pub binary_path: String,
+}
+
+/// options used when packaging services
+#[derive(Debug, Clone, Default)]
+pub struct PackageOptions {
+    /// sorted entries, owner 0:0, fixed permissions and timestamps
+    pub reproducible: bool,
+
+    /// timestamp (seconds) used for layer entries and the image config in reproducible mode
+    pub source_date_epoch: i64,
+
+    /// layer compression set on the command line (takes precedence over the config)
+    pub compression: Option<LayerCompression>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LayerCompression {
+    #[default]
+    Gzip,
+    Zstd,
+    None,
+}
+
+/// microservices.lock, the manifest digest staged for each service
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Lockfile {
+    #[serde(rename = "services")]
+    pub services: Vec<LockedService>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LockedService {
+    #[serde(rename = "name")]
+    pub name: String,
+
+    /// the registry reference the digest was resolved from
+    #[serde(rename = "registry")]
+    pub registry: String,
+
+    #[serde(rename = "digest")]
+    pub digest: String,
+}
+
+/// trust policy, the first rule matching a service selects the trusted keys
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TrustPolicy {
+    /// named public keys referenced by the rules
+    #[serde(default)]
+    #[serde(rename = "keys")]
+    pub keys: Vec<TrustedKey>,
+
+    #[serde(rename = "rules")]
+    pub rules: Vec<TrustRule>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TrustedKey {
+    #[serde(rename = "id")]
+    pub id: String,
+
+    /// public key path (relative to the policy file), env:<VAR> or fd:<N>
+    #[serde(rename = "path")]
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TrustRule {
+    /// service name or glob pattern (any service when not set)
+    #[serde(rename = "service")]
+    pub service: Option<String>,
+
+    /// registry, namespace or repository prefix e.g. quay.io/acme (any registry when not set)
+    #[serde(rename = "registry")]
+    pub registry: Option<String>,
+
+    /// ids of keys declared in the policy, a signature from any of them is trusted
+    #[serde(default)]
+    #[serde(rename = "keys")]
+    pub keys: Vec<String>,
+
+    #[serde(default = "default_true")]
+    #[serde(rename = "requireSignature")]
+    pub require_signature: bool,
+}
+
+fn default_true() -> bool {
+    true
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -496,7 +1014,7 @@ pub struct Artifact {
     pub name: String,
 
     #[serde(rename = "artifactType")]
-    pub artitact_type: String,
+    pub artifact_type: String,
 }
 
 #[allow(unused)]
@@ -534,9 +1052,238 @@ pub struct APIParameters {
 
     #[serde(rename = "subnet")]
     pub subnet: Option<u8>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "registration")]
+    pub registration: Option<Registration>,
+
+    /// request id, echoed back by workers in the response
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "id")]
+    pub id: Option<String>,
+
+    /// manifest digest resolved for each service (stage from registry), workers pull exactly these
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "digests")]
+    pub digests: Option<HashMap<String, String>>,
+
+    /// stage installs services without a signature
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "insecureAllowUnsigned")]
+    pub insecure_allow_unsigned: Option<bool>,
+
+    /// package normalizes layer entries and timestamps
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "reproducible")]
+    pub reproducible: Option<bool>,
+
+    /// package layer compression, overrides the compression set in the config
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "compression")]
+    pub compression: Option<LayerCompression>,
+
+    /// package signing key location on the worker (path or env:<VAR>)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "privateKey")]
+    pub private_key: Option<String>,
+
+    /// api token of cli clients, removed before a command is forwarded to the workers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "apiToken")]
+    pub api_token: Option<String>,
+}
+
+/// request body for the controller http api (stage, start and stop)
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct CommandRequest {
+    /// hostname of the node or all
+    #[serde(rename = "node")]
+    pub node: String,
+
+    /// the service to start or stop
+    #[serde(rename = "service")]
+    pub service: Option<String>,
+
+    #[serde(rename = "configFile")]
+    pub config_file: String,
+
+    #[serde(rename = "workingDir")]
+    pub working_dir: String,
+
+    #[serde(rename = "fromRegistry")]
+    pub from_registry: Option<bool>,
+
+    #[serde(rename = "skipTlsVerify")]
+    pub skip_tls_verify: Option<bool>,
+
+    /// resolve tags again instead of using the digests in microservices.lock (stage, resolved by the controller)
+    #[serde(rename = "update")]
+    pub update: Option<bool>,
+
+    /// install services without a signature (stage)
+    #[serde(rename = "insecureAllowUnsigned")]
+    pub insecure_allow_unsigned: Option<bool>,
+
+    /// seconds to wait for the workers to respond (default 60)
+    #[serde(rename = "timeout")]
+    pub timeout: Option<u64>,
+}
+
+/// settings shared by the cli, worker and controller (~/.config/mpm/config.yaml)
+/// cli flags take precedence over these values
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Settings {
+    /// controller endpoint used by the worker and cli commands
+    #[serde(rename = "controller")]
+    pub controller: Option<String>,
+
+    /// endpoint the controller listens on
+    #[serde(rename = "listen")]
+    pub listen: Option<String>,
+
+    #[serde(rename = "dataDir")]
+    pub data_dir: Option<String>,
+
+    /// directory holding the signing keys
+    #[serde(rename = "keyDir")]
+    pub key_dir: Option<String>,
+
+    /// trust policy evaluated by verify and stage
+    #[serde(rename = "trustPolicy")]
+    pub trust_policy: Option<String>,
+
+    #[serde(rename = "joinToken")]
+    pub join_token: Option<String>,
+
+    /// api token presented by cli commands
+    #[serde(rename = "apiToken")]
+    pub api_token: Option<String>,
+
+    #[serde(rename = "logLevel")]
+    pub log_level: Option<String>,
+
+    /// address the controller serves the http api on
+    #[serde(rename = "apiListen")]
+    pub api_listen: Option<String>,
+
+    #[serde(rename = "tls")]
+    pub tls: Option<TlsSettings>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TlsSettings {
+    /// certificate (pem) presented by the controller on wss
+    #[serde(rename = "cert")]
+    pub cert: Option<String>,
+
+    /// private key (pkcs8 pem) of the controller certificate
+    #[serde(rename = "key")]
+    pub key: Option<String>,
+
+    /// additional ca certificate (pem) trusted by workers and cli
+    #[serde(rename = "ca")]
+    pub ca: Option<String>,
+}
+
+/// details a worker sends to the controller when it connects
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Registration {
+    /// join token, only used on first enrollment
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "token")]
+    pub token: Option<String>,
+
+    /// worker public key (pem) to be signed by the controller on enrollment
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "publicKey")]
+    pub public_key: Option<String>,
+
+    /// worker certificate (pem) issued by the controller on a previous enrollment
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "certificate")]
+    pub certificate: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "timestamp")]
+    pub timestamp: Option<u64>,
+
+    /// base64 signature of "node:timestamp" created with the worker private key
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "signature")]
+    pub signature: Option<String>,
+}
+
+/// liveness of a registered worker as tracked by the controller
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, JsonSchema)]
+pub enum NodeStatus {
+    Ready,
+    NotReady,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct NodeInfo {
+    #[serde(rename = "name")]
+    pub name: String,
+
+    #[serde(rename = "status")]
+    pub status: NodeStatus,
+
+    /// unix timestamp of the last heartbeat received
+    #[serde(rename = "lastSeen")]
+    pub last_seen: u64,
+}
+
+/// last known state of a service deployed on a node
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct DeployedService {
+    #[serde(rename = "node")]
+    pub node: String,
+
+    #[serde(rename = "name")]
+    pub name: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "version")]
+    pub version: Option<String>,
+
+    /// digest of the staged oci manifest
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "digest")]
+    pub digest: Option<String>,
+
+    /// staged, running, stopped or failed
+    #[serde(rename = "status")]
+    pub status: String,
+
+    /// unix timestamp of the last update
+    #[serde(rename = "updated")]
+    pub updated: u64,
 }
 
+/// controller state persisted under {data-dir}/state/state.json
+#[derive(Serialize, Deserialize, Debug, Clone, Default, JsonSchema)]
+pub struct ControllerState {
+    #[serde(rename = "nodes")]
+    pub nodes: Vec<NodeInfo>,
+
+    #[serde(rename = "services")]
+    pub services: Vec<DeployedService>,
+}
+
+/// join token as persisted on the controller (only the hash of the secret is stored)
 #[derive(Serialize, Deserialize, Debug)]
+pub struct JoinToken {
+    #[serde(rename = "id")]
+    pub id: String,
+
+    #[serde(rename = "hash")]
+    pub hash: String,
+
+    #[serde(rename = "expires")]
+    pub expires: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
 pub struct APIResponse {
     #[serde(rename = "status")]
     pub status: String,
@@ -549,4 +1296,140 @@ pub struct APIResponse {
 
     #[serde(rename = "text")]
     pub text: String,
+
+    /// certificate (pem) issued to a worker on enrollment
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "credential")]
+    pub credential: Option<String>,
+
+    /// services affected by the command (recorded by the controller)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "services")]
+    pub services: Option<Vec<DeployedService>>,
+
+    /// id of the request this is a response to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "id")]
+    pub id: Option<String>,
+
+    /// jobs tracked by the controller (jobs list and jobs wait)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "jobs")]
+    pub jobs: Option<Vec<Job>>,
+}
+
+/// kind of event published by the controller
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    NodeJoined,
+    NodeLeft,
+    ServiceStaged,
+    ServiceStarted,
+    ServiceStopped,
+    ServiceFailed,
+    ServiceExited,
+    ServiceRestarted,
+    SignatureVerificationFailed,
+    NetworkCreated,
+}
+
+/// structured event, sent by workers and streamed to subscribers by the controller
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct Event {
+    #[serde(rename = "event")]
+    pub kind: EventKind,
+
+    #[serde(rename = "node")]
+    pub node: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "service")]
+    pub service: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "message")]
+    pub message: Option<String>,
+
+    /// unix timestamp set by the controller when the event is published
+    #[serde(rename = "timestamp")]
+    pub timestamp: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, JsonSchema)]
+pub enum JobStatus {
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+/// outcome of a job on a single node
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct JobResult {
+    #[serde(rename = "node")]
+    pub node: String,
+
+    #[serde(rename = "status")]
+    pub status: String,
+
+    #[serde(rename = "text")]
+    pub text: String,
+}
+
+/// a stage or package command tracked by the controller
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct Job {
+    #[serde(rename = "id")]
+    pub id: String,
+
+    #[serde(rename = "command")]
+    pub command: String,
+
+    /// hostname of the node or all
+    #[serde(rename = "node")]
+    pub node: String,
+
+    #[serde(rename = "status")]
+    pub status: JobStatus,
+
+    /// nodes that have not reported a result yet
+    #[serde(rename = "pending")]
+    pub pending: Vec<String>,
+
+    #[serde(rename = "results")]
+    pub results: Vec<JobResult>,
+
+    /// last progress message reported by a worker
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "progress")]
+    pub progress: Option<String>,
+
+    #[serde(rename = "created")]
+    pub created: u64,
+
+    #[serde(rename = "updated")]
+    pub updated: u64,
+}
+
+/// progress reported by a worker while running a job
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct Progress {
+    #[serde(rename = "job")]
+    pub job: String,
+
+    #[serde(rename = "node")]
+    pub node: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "service")]
+    pub service: Option<String>,
+
+    #[serde(rename = "message")]
+    pub message: String,
+
+    /// bytes transferred so far (blob downloads and uploads)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "bytes")]
+    pub bytes: Option<u64>,
 }
@@ -1,41 +1,113 @@
 use crate::api::schema::Service;
 use custom_logger::*;
 use mirror_error::MirrorError;
-use std::env::set_current_dir;
-use std::process::{Command, Stdio};
+use std::collections::HashMap;
+use std::fs;
+use std::process::{Child, Command, Stdio};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+use tokio::time::sleep;
+
+// microservices started by this worker, they keep running across controller reconnects
+static SERVICES: LazyLock<Mutex<HashMap<String, Child>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
 
 pub async fn start_service(working_dir: String, service: Service) -> Result<(), MirrorError> {
     let dir = format!("{}/microservices/{}", working_dir, service.name);
-    let _cd = set_current_dir(&dir);
-    let mut start_ms = Command::new(format!("./{}", service.name));
+    if let Some(child) = SERVICES.lock().unwrap().get_mut(&service.name) {
+        if child
+            .try_wait()
+            .map(|status| status.is_none())
+            .unwrap_or(false)
+        {
+            return Err(MirrorError::new(&format!(
+                "service {} is already running (pid {})",
+                service.name,
+                child.id()
+            )));
+        }
+    }
+    let binary = fs::canonicalize(format!("{}/{}", dir, service.name));
+    if binary.is_err() {
+        return Err(MirrorError::new(&format!(
+            "service binary not found (maybe needs to be staged ?) {}",
+            binary.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    let mut start_ms = Command::new(binary.unwrap());
+    start_ms.current_dir(&dir);
     if service.args.is_some() {
         for arg in service.args.unwrap().iter() {
             start_ms.arg(arg.name.clone());
             start_ms.arg(arg.value.clone());
         }
     }
-    let res = start_ms.spawn().unwrap().wait_with_output();
+    let res = start_ms.spawn();
     if res.is_err() {
         return Err(MirrorError::new(&format!(
             "{}",
-            String::from_utf8_lossy(&res.unwrap().stderr)
+            res.err().unwrap().to_string().to_lowercase()
         )));
     }
-    if res.is_ok() {
-        let err_response = format!("{}", String::from_utf8_lossy(&res.as_ref().unwrap().stderr));
-        if err_response.contains("ERROR") {
+    let mut child = res.unwrap();
+    // give the service a moment to fail on startup (bad args, missing config)
+    sleep(Duration::from_millis(500)).await;
+    if let Ok(Some(status)) = child.try_wait() {
+        if !status.success() {
             return Err(MirrorError::new(&format!(
-                "{}",
-                String::from_utf8_lossy(&res.unwrap().stderr)
+                "service {} exited on startup with {}",
+                service.name, status
             )));
         }
     }
-    let response = format!("{}", String::from_utf8_lossy(&res.as_ref().unwrap().stdout));
-    info!("start_service] microservice {}", &response);
+    info!(
+        "[start_service] microservice {} started with pid {}",
+        service.name,
+        child.id()
+    );
+    SERVICES.lock().unwrap().insert(service.name, child);
     Ok(())
 }
 
+// remove services that have exited from the process table
+// returns the name and exit reason of each removed service
+pub fn reap_services() -> Vec<(String, String)> {
+    let mut services = SERVICES.lock().unwrap();
+    let mut exited = vec![];
+    services.retain(|name, child| match child.try_wait() {
+        Ok(Some(status)) => {
+            warn!(
+                "[reap_services] microservice {} exited with {}",
+                name, status
+            );
+            exited.push((name.clone(), format!("exited with {}", status)));
+            false
+        }
+        Ok(None) => true,
+        Err(err) => {
+            error!("[reap_services] microservice {} {}", name, err);
+            exited.push((name.clone(), err.to_string().to_lowercase()));
+            false
+        }
+    });
+    exited
+}
+
 pub async fn stop_service(service: String) -> Result<(), MirrorError> {
+    let tracked = SERVICES.lock().unwrap().remove(&service);
+    if let Some(mut child) = tracked {
+        let res = child.kill();
+        if res.is_err() {
+            return Err(MirrorError::new(&format!(
+                "stopping service {} {}",
+                service,
+                res.err().unwrap().to_string().to_lowercase()
+            )));
+        }
+        let _ = child.wait();
+        info!("[stop_service] microservice {} stopped", service);
+        return Ok(());
+    }
     let ps = Command::new("ps")
         .arg("ef")
         .stdout(Stdio::piped())
@@ -1,3 +1,8 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
 pub fn console_icon_err() {
     println!("\x1b[1A\x1b[36C{}", "\x1b[1;91m✗\x1b[0m");
 }
@@ -5,3 +10,34 @@ pub fn console_icon_err() {
 pub fn console_icon_ok() {
     println!("\x1b[1A\x1b[36C{}", "\x1b[1;92m✓\x1b[0m");
 }
+
+// seconds since the unix epoch
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+// write a file that should only be readable by the owner (keys, tokens)
+pub fn write_private_file(path: String, data: &[u8]) -> Result<(), std::io::Error> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(data)?;
+    Ok(())
+}
+
+// oci architecture name of this host
+pub fn oci_architecture() -> String {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64".to_string(),
+        "aarch64" => "arm64".to_string(),
+        "x86" => "386".to_string(),
+        "powerpc64" => "ppc64le".to_string(),
+        arch => arch.to_string(),
+    }
+}
@@ -0,0 +1,179 @@
+use mirror_error::MirrorError;
+
+// default websocket port for the controller
+pub const DEFAULT_PORT: u16 = 2000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Endpoint {
+    pub scheme: String,
+    pub host: String,
+    pub port: u16,
+}
+
+impl Endpoint {
+    // host with square brackets for ipv6 literals
+    fn host_literal(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        }
+    }
+
+    // host:port, suitable for binding a listener
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.host_literal(), self.port)
+    }
+
+    // websocket url used to connect to the controller
+    pub fn url(&self) -> String {
+        format!("{}://{}:{}", self.scheme, self.host_literal(), self.port)
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.scheme == "wss"
+    }
+}
+
+// parse an endpoint in the form [ws://|wss://]host[:port]
+// ipv6 literals are accepted with or without square brackets ([::1]:2000 or ::1)
+pub fn parse_endpoint(value: &str) -> Result<Endpoint, MirrorError> {
+    let value = value.trim().trim_end_matches('/');
+    let (scheme, rest) = match value.split_once("://") {
+        Some((scheme, rest)) => (scheme.to_lowercase(), rest),
+        None => ("ws".to_string(), value),
+    };
+    if scheme != "ws" && scheme != "wss" {
+        return Err(MirrorError::new(&format!(
+            "endpoint {} has an unsupported scheme (use ws or wss)",
+            value
+        )));
+    }
+    let (host, port) = if let Some(bracketed) = rest.strip_prefix('[') {
+        match bracketed.split_once(']') {
+            Some((host, "")) => (host, None),
+            Some((host, port)) => match port.strip_prefix(':') {
+                Some(port) => (host, Some(port)),
+                None => {
+                    return Err(MirrorError::new(&format!(
+                        "endpoint {} is malformed",
+                        value
+                    )))
+                }
+            },
+            None => {
+                return Err(MirrorError::new(&format!(
+                    "endpoint {} is missing a closing bracket",
+                    value
+                )))
+            }
+        }
+    } else if rest.matches(':').count() > 1 {
+        // bare ipv6 literal, no port
+        (rest, None)
+    } else {
+        match rest.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (rest, None),
+        }
+    };
+    if host.is_empty() {
+        return Err(MirrorError::new(&format!(
+            "endpoint {} is missing a host",
+            value
+        )));
+    }
+    let port = match port {
+        Some(port) => match port.parse::<u16>() {
+            Ok(port) => port,
+            Err(_) => {
+                return Err(MirrorError::new(&format!(
+                    "endpoint {} has an invalid port",
+                    value
+                )))
+            }
+        },
+        None => DEFAULT_PORT,
+    };
+    Ok(Endpoint {
+        scheme,
+        host: host.to_string(),
+        port,
+    })
+}
+
+// use the first configured value (cli flag, config file) or fall back to the default
+pub fn resolve_endpoint(
+    values: Vec<Option<String>>,
+    default: &str,
+) -> Result<Endpoint, MirrorError> {
+    let value = values
+        .into_iter()
+        .flatten()
+        .next()
+        .unwrap_or(default.to_string());
+    parse_endpoint(&value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(scheme: &str, host: &str, port: u16) -> Endpoint {
+        Endpoint {
+            scheme: scheme.to_string(),
+            host: host.to_string(),
+            port,
+        }
+    }
+
+    #[test]
+    fn schemes() {
+        assert_eq!(
+            parse_endpoint("ws://controller:2001").unwrap(),
+            endpoint("ws", "controller", 2001)
+        );
+        let secure = parse_endpoint("WSS://controller:2443/").unwrap();
+        assert_eq!(secure, endpoint("wss", "controller", 2443));
+        assert!(secure.is_tls());
+        assert_eq!(secure.url(), "wss://controller:2443");
+        assert!(parse_endpoint("http://controller:2000").is_err());
+    }
+
+    #[test]
+    fn default_port() {
+        assert_eq!(
+            parse_endpoint("controller").unwrap(),
+            endpoint("ws", "controller", DEFAULT_PORT)
+        );
+        assert_eq!(
+            parse_endpoint("wss://192.168.1.10").unwrap(),
+            endpoint("wss", "192.168.1.10", DEFAULT_PORT)
+        );
+    }
+
+    #[test]
+    fn ipv6_literals() {
+        let bracketed = parse_endpoint("[::1]:2000").unwrap();
+        assert_eq!(bracketed, endpoint("ws", "::1", 2000));
+        assert_eq!(bracketed.address(), "[::1]:2000");
+        assert_eq!(
+            parse_endpoint("wss://[fd00::10]").unwrap(),
+            endpoint("wss", "fd00::10", DEFAULT_PORT)
+        );
+        let bare = parse_endpoint("fd00::10").unwrap();
+        assert_eq!(bare, endpoint("ws", "fd00::10", DEFAULT_PORT));
+        assert_eq!(bare.url(), "ws://[fd00::10]:2000");
+        assert!(parse_endpoint("[::1").is_err());
+        assert!(parse_endpoint("[::1]2000").is_err());
+    }
+
+    #[test]
+    fn bad_port() {
+        assert!(parse_endpoint("controller:http").is_err());
+        assert!(parse_endpoint("controller:70000").is_err());
+        assert!(parse_endpoint("controller:").is_err());
+        assert!(parse_endpoint("[::1]:x").is_err());
+        assert!(parse_endpoint(":2000").is_err());
+    }
+}
@@ -0,0 +1,98 @@
+use crate::api::schema::{LockedService, Lockfile};
+use mirror_error::MirrorError;
+use mirror_utils::fs_handler;
+use std::path::Path;
+
+const LOCK_FILE: &str = "microservices.lock";
+
+// the lockfile lives next to the config file
+pub fn lock_path(config_file: &str) -> String {
+    Path::new(config_file)
+        .with_file_name(LOCK_FILE)
+        .to_string_lossy()
+        .to_string()
+}
+
+// read the lockfile, an empty lockfile when it does not exist yet
+pub async fn load_lock(config_file: &str) -> Result<Lockfile, MirrorError> {
+    let path = lock_path(config_file);
+    if !Path::new(&path).exists() {
+        return Ok(Lockfile::default());
+    }
+    let data = fs_handler(path.clone(), "read", None).await?;
+    let res = serde_yaml::from_str::<Lockfile>(&data);
+    if res.is_err() {
+        return Err(MirrorError::new(&format!(
+            "[load_lock] {} {}",
+            path,
+            res.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    Ok(res.unwrap())
+}
+
+pub async fn save_lock(config_file: &str, lock: &Lockfile) -> Result<(), MirrorError> {
+    let data = serde_yaml::to_string(lock).unwrap();
+    fs_handler(lock_path(config_file), "write", Some(data)).await?;
+    Ok(())
+}
+
+impl Lockfile {
+    // digest pinned for a service, ignored when the registry reference changed
+    pub fn digest(&self, name: &str, registry: &str) -> Option<String> {
+        self.services
+            .iter()
+            .find(|locked| locked.name == name && locked.registry == registry)
+            .map(|locked| locked.digest.clone())
+    }
+
+    pub fn pin(&mut self, name: &str, registry: &str, digest: &str) {
+        self.services.retain(|locked| locked.name != name);
+        self.services.push(LockedService {
+            name: name.to_string(),
+            registry: registry.to_string(),
+            digest: digest.to_string(),
+        });
+        self.services.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lockfile_next_to_config() {
+        assert_eq!(
+            lock_path("config/microservices.yaml"),
+            "config/microservices.lock"
+        );
+        assert_eq!(lock_path("microservices.yaml"), "microservices.lock");
+    }
+
+    #[test]
+    fn pin_replaces_and_sorts() {
+        let mut lock = Lockfile::default();
+        lock.pin("b", "quay.io/acme/b:v1", "sha256:b1");
+        lock.pin("a", "quay.io/acme/a:v1", "sha256:a1");
+        lock.pin("b", "quay.io/acme/b:v1", "sha256:b2");
+        let names = lock
+            .services
+            .iter()
+            .map(|locked| locked.name.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(names, vec!["a", "b"]);
+        assert_eq!(
+            lock.digest("b", "quay.io/acme/b:v1").as_deref(),
+            Some("sha256:b2")
+        );
+    }
+
+    #[test]
+    fn changed_registry_is_not_pinned() {
+        let mut lock = Lockfile::default();
+        lock.pin("a", "quay.io/acme/a:v1", "sha256:a1");
+        assert_eq!(lock.digest("a", "quay.io/acme/a:v2"), None);
+        assert_eq!(lock.digest("c", "quay.io/acme/a:v1"), None);
+    }
+}
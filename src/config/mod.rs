@@ -1 +1,4 @@
+pub mod endpoint;
+pub mod lock;
 pub mod read;
+pub mod settings;
@@ -1,6 +1,8 @@
 use crate::api::schema::*;
+use crate::common::utils::oci_architecture;
 use mirror_error::MirrorError;
 use mirror_utils::fs_handler;
+use std::path::Path;
 
 // read the 'image set config' file
 pub async fn load_config(config_file: String) -> Result<String, MirrorError> {
@@ -23,6 +25,34 @@ pub fn parse_yaml_config(data: String) -> Result<MicroserviceConfig, MirrorError
     Ok(root)
 }
 
+// relative binary paths and file sources are resolved against the directory of the config file
+pub fn resolve_service_paths(
+    mut config: MicroserviceConfig,
+    config_file: &str,
+) -> MicroserviceConfig {
+    let base_dir = Path::new(config_file).parent().unwrap_or(Path::new("."));
+    let resolve = |path: &mut String| {
+        if !Path::new(path.as_str()).is_absolute() {
+            *path = base_dir.join(path.as_str()).to_string_lossy().to_string();
+        }
+    };
+    for service in config.spec.services.iter_mut() {
+        resolve(&mut service.binary_path);
+        for platform in service.platforms.iter_mut().flatten() {
+            resolve(&mut platform.binary_path);
+        }
+        for file in service.files.iter_mut().flatten() {
+            resolve(&mut file.source);
+        }
+        for group in service.layers.iter_mut().flatten() {
+            for file in group.files.iter_mut() {
+                resolve(&mut file.source);
+            }
+        }
+    }
+    config
+}
+
 // get a specific service
 pub fn get_service(service: String, config: MicroserviceConfig) -> Service {
     let index = config
@@ -33,3 +63,16 @@ pub fn get_service(service: String, config: MicroserviceConfig) -> Service {
         .unwrap();
     return config.spec.services[index].clone();
 }
+
+// platforms to package a service for, the binaryPath is used for the host platform
+// when no platforms are listed
+pub fn service_platforms(service: &Service) -> Vec<Platform> {
+    match service.platforms.as_ref() {
+        Some(platforms) if !platforms.is_empty() => platforms.clone(),
+        _ => vec![Platform {
+            architecture: oci_architecture(),
+            os: None,
+            binary_path: service.binary_path.clone(),
+        }],
+    }
+}
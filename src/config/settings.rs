@@ -0,0 +1,43 @@
+use crate::api::schema::Settings;
+use mirror_error::MirrorError;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+// default location of the settings file ($XDG_CONFIG_HOME/mpm/config.yaml or ~/.config/mpm/config.yaml)
+fn default_settings_file() -> Option<String> {
+    if let Ok(dir) = env::var("XDG_CONFIG_HOME") {
+        return Some(format!("{}/mpm/config.yaml", dir));
+    }
+    env::var("HOME")
+        .ok()
+        .map(|home| format!("{}/.config/mpm/config.yaml", home))
+}
+
+// load the cli/worker/controller settings, a missing default file is not an error
+pub fn load_settings(config: Option<String>) -> Result<Settings, MirrorError> {
+    let file = match config {
+        Some(file) => file,
+        None => match default_settings_file() {
+            Some(file) if Path::new(&file).exists() => file,
+            _ => return Ok(Settings::default()),
+        },
+    };
+    let res = fs::read_to_string(&file);
+    if res.is_err() {
+        return Err(MirrorError::new(&format!(
+            "[load_settings] reading {} {}",
+            file,
+            res.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    let res_yaml = serde_yaml::from_str::<Settings>(&res.unwrap());
+    if res_yaml.is_err() {
+        return Err(MirrorError::new(&format!(
+            "[load_settings] parsing {} {}",
+            file,
+            res_yaml.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    Ok(res_yaml.unwrap())
+}
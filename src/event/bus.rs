@@ -0,0 +1,59 @@
+use crate::api::schema::{Event, EventKind};
+use crate::common::utils::unix_timestamp;
+use custom_logger::*;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::{channel, Receiver, Sender};
+
+// number of past events replayed to a new subscriber
+const HISTORY_SIZE: usize = 256;
+
+// controller events channel, keeps a short history for subscribers that connect later
+#[derive(Clone)]
+pub struct EventBus {
+    tx: Sender<Event>,
+    history: Arc<Mutex<VecDeque<Event>>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (tx, _) = channel(64);
+        Self {
+            tx,
+            history: Arc::new(Mutex::new(VecDeque::with_capacity(HISTORY_SIZE))),
+        }
+    }
+
+    pub fn publish(&self, mut event: Event) {
+        event.timestamp = unix_timestamp();
+        debug!("[event] {:?} {}", event.kind, event.node);
+        let mut history = self.history.lock().unwrap();
+        if history.len() == HISTORY_SIZE {
+            history.pop_front();
+        }
+        history.push_back(event.clone());
+        // no subscribers is not an error
+        let _ = self.tx.send(event);
+    }
+
+    // returns the event history and a receiver for new events
+    pub fn subscribe(&self) -> (Vec<Event>, Receiver<Event>) {
+        let history = self.history.lock().unwrap();
+        (history.iter().cloned().collect(), self.tx.subscribe())
+    }
+}
+
+pub fn new_event(
+    kind: EventKind,
+    node: &str,
+    service: Option<&str>,
+    message: Option<String>,
+) -> Event {
+    Event {
+        kind,
+        node: node.to_string(),
+        service: service.map(|s| s.to_string()),
+        message,
+        timestamp: 0,
+    }
+}
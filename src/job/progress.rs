@@ -0,0 +1,69 @@
+use crate::api::schema::{EventKind, Progress};
+use crate::event::bus::new_event;
+use gethostname::gethostname;
+use mirror_error::MirrorError;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc::Sender;
+
+// sends progress messages (and events) back to the controller,
+// does nothing when a command runs locally
+#[derive(Clone)]
+pub struct ProgressReporter {
+    job: Option<String>,
+    tx: Option<Sender<String>>,
+    // set when the job is cancelled, blocking work (unpacking, archiving) checks it
+    // between steps as aborting the task only takes effect at an await point
+    cancelled: Arc<AtomicBool>,
+}
+
+impl ProgressReporter {
+    pub fn new(job: Option<String>, tx: Sender<String>) -> Self {
+        Self {
+            job,
+            tx: Some(tx),
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn disabled() -> Self {
+        Self {
+            job: None,
+            tx: None,
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn check_cancelled(&self) -> Result<(), MirrorError> {
+        match self.cancelled.load(Ordering::SeqCst) {
+            true => Err(MirrorError::new("job cancelled")),
+            false => Ok(()),
+        }
+    }
+
+    pub async fn report(&self, service: &str, message: &str, bytes: Option<u64>) {
+        if let (Some(job), Some(tx)) = (self.job.as_ref(), self.tx.as_ref()) {
+            let progress = Progress {
+                job: job.clone(),
+                node: gethostname().to_string_lossy().to_string(),
+                service: Some(service.to_string()),
+                message: message.to_string(),
+                bytes,
+            };
+            let _ = tx.send(serde_json::to_string(&progress).unwrap()).await;
+        }
+    }
+
+    // publish an event on the controller (also for commands that are not tracked as jobs)
+    pub async fn event(&self, kind: EventKind, service: &str, message: Option<String>) {
+        if let Some(tx) = self.tx.as_ref() {
+            let node = gethostname().to_string_lossy().to_string();
+            let event = new_event(kind, &node, Some(service), message);
+            let _ = tx.send(serde_json::to_string(&event).unwrap()).await;
+        }
+    }
+}
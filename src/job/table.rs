@@ -0,0 +1,135 @@
+use crate::api::schema::{APIResponse, Job, JobResult, JobStatus, Progress};
+use crate::common::utils::unix_timestamp;
+use mirror_error::MirrorError;
+use rand::{
+    distr::{Alphanumeric, SampleString},
+    rng,
+};
+
+// number of jobs kept by the controller (oldest finished jobs are dropped first)
+const MAX_JOBS: usize = 100;
+
+pub fn new_job_id() -> String {
+    Alphanumeric.sample_string(&mut rng(), 12).to_lowercase()
+}
+
+// a job is done once every node it was sent to has reported a result
+fn finish(job: &mut Job) {
+    if job.status == JobStatus::Running && job.pending.is_empty() {
+        job.status = match job.results.iter().all(|result| result.status == "OK") {
+            true => JobStatus::Completed,
+            false => JobStatus::Failed,
+        };
+    }
+}
+
+// in memory list of the jobs submitted to the controller
+pub struct JobTable {
+    jobs: Vec<Job>,
+}
+
+impl JobTable {
+    pub fn new() -> Self {
+        Self { jobs: vec![] }
+    }
+
+    pub fn create(&mut self, id: &str, command: &str, node: &str, nodes: Vec<String>) -> Job {
+        if self.jobs.len() >= MAX_JOBS {
+            if let Some(pos) = self
+                .jobs
+                .iter()
+                .position(|job| job.status != JobStatus::Running)
+            {
+                self.jobs.remove(pos);
+            }
+        }
+        let now = unix_timestamp();
+        let job = Job {
+            id: id.to_string(),
+            command: command.to_string(),
+            node: node.to_string(),
+            status: JobStatus::Running,
+            pending: nodes,
+            results: vec![],
+            progress: None,
+            created: now,
+            updated: now,
+        };
+        self.jobs.push(job.clone());
+        job
+    }
+
+    pub fn get(&self, id: &str) -> Option<Job> {
+        self.jobs.iter().find(|job| job.id == id).cloned()
+    }
+
+    pub fn list(&self) -> Vec<Job> {
+        self.jobs.clone()
+    }
+
+    pub fn progress(&mut self, progress: &Progress) {
+        if let Some(job) = self.jobs.iter_mut().find(|job| job.id == progress.job) {
+            job.progress = Some(match progress.service.as_ref() {
+                Some(service) => format!("{} {} {}", progress.node, service, progress.message),
+                None => format!("{} {}", progress.node, progress.message),
+            });
+            job.updated = unix_timestamp();
+        }
+    }
+
+    // record the result reported by a node, returns the job once it has finished
+    pub fn record(&mut self, node: &str, response: &APIResponse) -> Option<Job> {
+        let id = response.id.as_ref()?;
+        let job = self
+            .jobs
+            .iter_mut()
+            .find(|job| &job.id == id && job.status == JobStatus::Running)?;
+        if !job.pending.iter().any(|pending| pending == node) {
+            return None;
+        }
+        job.pending.retain(|pending| pending != node);
+        job.results.push(JobResult {
+            node: node.to_string(),
+            status: response.status.clone(),
+            text: response.text.clone(),
+        });
+        job.updated = unix_timestamp();
+        finish(job);
+        match job.status {
+            JobStatus::Running => None,
+            _ => Some(job.clone()),
+        }
+    }
+
+    // fail the pending part of running jobs sent to a node that went away
+    pub fn node_lost(&mut self, node: &str) {
+        for job in self.jobs.iter_mut() {
+            if job.status == JobStatus::Running && job.pending.iter().any(|p| p == node) {
+                job.pending.retain(|pending| pending != node);
+                job.results.push(JobResult {
+                    node: node.to_string(),
+                    status: "KO".to_string(),
+                    text: "node disconnected".to_string(),
+                });
+                job.updated = unix_timestamp();
+                finish(job);
+            }
+        }
+    }
+
+    pub fn cancel(&mut self, id: &str) -> Result<Job, MirrorError> {
+        let job = self.jobs.iter_mut().find(|job| job.id == id);
+        match job {
+            Some(job) if job.status == JobStatus::Running => {
+                job.status = JobStatus::Cancelled;
+                job.updated = unix_timestamp();
+                Ok(job.clone())
+            }
+            Some(job) => Err(MirrorError::new(&format!(
+                "job {} is not running ({:?})",
+                id, job.status
+            ))),
+            None => Err(MirrorError::new(&format!("job {} not found", id))),
+        }
+    }
+}
@@ -1,6 +1,16 @@
 use crate::api::schema::*;
+use crate::config::endpoint::resolve_endpoint;
+use crate::config::settings::load_settings;
+use crate::job::progress::ProgressReporter;
+use crate::node::token::{create_api_token, create_token};
 use crate::package::create::*;
-use crate::package::signature::{create_keypair, sign_artifact, verify_artifact};
+use crate::package::signature::{
+    create_keypair, load_private_key, private_key_source, public_key_source, read_signature,
+    sign_artifact, signature_dir, signature_path,
+};
+use crate::package::trust::{
+    load_trust_policy, single_key_policy, trust_policy_path, TrustStore, Verification,
+};
 use crate::websocket::client::*;
 use crate::websocket::server::*;
 use clap::Parser;
@@ -8,15 +18,21 @@ use custom_logger::*;
 use mirror_error::MirrorError;
 use remote::process::{remote_execute, remote_upload};
 use std::process;
-use workflow::handler;
+use workflow::{handler, transfer};
 
 mod api;
 mod command;
 mod common;
 mod config;
+mod event;
+mod job;
 mod network;
+mod node;
+mod oci;
 mod package;
 mod remote;
+mod rest;
+mod store;
 mod websocket;
 mod workflow;
 
@@ -24,7 +40,21 @@ mod workflow;
 async fn main() -> Result<(), MirrorError> {
     let args = Cli::parse();
 
-    let lvl = args.loglevel.as_ref().unwrap();
+    // cli flags take precedence over the settings file
+    let settings = load_settings(args.config.clone());
+    let settings = match settings {
+        Ok(settings) => settings,
+        Err(err) => {
+            eprintln!("{}", err.to_string().to_lowercase());
+            process::exit(1);
+        }
+    };
+
+    let lvl = args
+        .loglevel
+        .clone()
+        .or(settings.log_level.clone())
+        .unwrap_or("info".to_string());
     let l = match lvl.as_str() {
         "info" => LevelFilter::Info,
         "debug" => LevelFilter::Debug,
@@ -40,20 +70,62 @@ async fn main() -> Result<(), MirrorError> {
     } else {
         mode = args.mode.as_ref().unwrap()
     }
-    let server_ip = match args.server_ip {
-        None => "127.0.0.1".to_string(),
-        Some(ip) => ip,
-    };
+    // --server-ip is kept for compatibility, it only sets the host (default port)
+    let controller = resolve_endpoint(
+        vec![
+            args.controller.clone(),
+            args.server_ip.clone(),
+            settings.controller.clone(),
+        ],
+        "127.0.0.1",
+    );
+    let listen = resolve_endpoint(
+        vec![
+            args.listen.clone(),
+            args.server_ip.clone(),
+            settings.listen.clone(),
+        ],
+        "127.0.0.1",
+    );
+    if controller.is_err() || listen.is_err() {
+        let err = controller.err().or(listen.err()).unwrap();
+        error!("{}", err.to_string().to_lowercase());
+        process::exit(1);
+    }
+    let controller = controller.unwrap();
+    let listen = listen.unwrap();
+    let mut tls = settings.tls.clone().unwrap_or_default();
+    tls.cert = args.tls_cert.clone().or(tls.cert);
+    tls.key = args.tls_key.clone().or(tls.key);
+    tls.ca = args.tls_ca.clone().or(tls.ca);
+    let data_dir = args
+        .data_dir
+        .clone()
+        .or(settings.data_dir.clone())
+        .unwrap_or(".".to_string());
+    let key_dir = args
+        .key_dir
+        .clone()
+        .or(settings.key_dir.clone())
+        .unwrap_or(format!("{}/.ssh", data_dir));
+    let trust_policy = trust_policy_path(
+        &key_dir,
+        &args.trust_policy.clone().or(settings.trust_policy.clone()),
+    );
+    let join_token = args.join_token.clone().or(settings.join_token.clone());
+    let api_token = args.api_token.clone().or(settings.api_token.clone());
+    let api_listen = args.api_listen.clone().or(settings.api_listen.clone());
     match mode {
         "worker" => {
-            let res = start_client(server_ip).await;
+            let res =
+                start_client(controller, tls, data_dir, join_token, key_dir, trust_policy).await;
             if res.is_err() {
                 error!("worker {}", res.err().unwrap().to_string().to_lowercase(),);
                 process::exit(1);
             }
         }
         "controller" => {
-            let res = start_server(server_ip).await;
+            let res = start_server(listen, tls, data_dir, api_listen).await;
             if res.is_err() {
                 error!(
                     "controller {}",
@@ -67,8 +139,68 @@ async fn main() -> Result<(), MirrorError> {
                 config_file,
                 working_dir,
                 skip_tls_verify,
+                node: Some(node),
+                reproducible,
+                compression,
+                private_key,
             }) => {
-                let res = handler::package(working_dir, config_file, skip_tls_verify).await;
+                // package on a registered worker, tracked as a job by the controller,
+                // the key is read on the worker so a descriptor of this process can not be used
+                if private_key
+                    .as_deref()
+                    .is_some_and(|key| key.starts_with("fd:"))
+                {
+                    error!("package --private-key fd:<N> can not be used with --node");
+                    process::exit(1);
+                }
+                let api_params = APIParameters {
+                    command: "package".to_string(),
+                    node: node.clone(),
+                    service: "all".to_string(),
+                    config_file: Some(config_file.clone()),
+                    working_dir: Some(working_dir.clone()),
+                    from_registry: None,
+                    skip_tls_verify: Some(*skip_tls_verify),
+                    ip: None,
+                    subnet: None,
+                    registration: None,
+                    id: None,
+                    digests: None,
+                    insecure_allow_unsigned: None,
+                    reproducible: Some(*reproducible),
+                    compression: *compression,
+                    private_key: private_key.clone(),
+                    api_token: api_token.clone(),
+                };
+                let message = serde_json::to_string(&api_params).unwrap();
+                let res = send_message(message, &controller, &tls).await;
+                if res.is_err() {
+                    error!(
+                        "send message {}",
+                        res.err().unwrap().to_string().to_lowercase()
+                    );
+                } else {
+                    info!("package message sent")
+                }
+            }
+            Some(Commands::Package {
+                config_file,
+                working_dir,
+                skip_tls_verify,
+                node: None,
+                reproducible,
+                compression,
+                private_key,
+            }) => {
+                let res = handler::package(
+                    working_dir,
+                    config_file,
+                    skip_tls_verify,
+                    &private_key_source(&key_dir, private_key),
+                    &package_options(*reproducible, *compression),
+                    &ProgressReporter::disabled(),
+                )
+                .await;
                 if res.is_err() {
                     error!("package {}", res.err().unwrap().to_string().to_lowercase());
                     process::exit(1);
@@ -80,7 +212,20 @@ async fn main() -> Result<(), MirrorError> {
                 working_dir,
                 from_registry,
                 skip_tls_verify,
+                update,
+                insecure_allow_unsigned,
             }) => {
+                // tags are resolved once here so every node stages the same digest
+                let mut digests = None;
+                if *from_registry {
+                    let res =
+                        handler::resolve_digests(config_file, *skip_tls_verify, *update).await;
+                    if res.is_err() {
+                        error!("stage {}", res.err().unwrap().to_string().to_lowercase());
+                        process::exit(1);
+                    }
+                    digests = Some(res.unwrap());
+                }
                 let api_params = APIParameters {
                     command: "stage".to_string(),
                     node: node.clone(),
@@ -91,9 +236,17 @@ async fn main() -> Result<(), MirrorError> {
                     skip_tls_verify: Some(*skip_tls_verify),
                     ip: None,
                     subnet: None,
+                    registration: None,
+                    id: None,
+                    digests,
+                    insecure_allow_unsigned: Some(*insecure_allow_unsigned),
+                    reproducible: None,
+                    compression: None,
+                    private_key: None,
+                    api_token: api_token.clone(),
                 };
                 let message = serde_json::to_string(&api_params).unwrap();
-                let res = send_message(message, server_ip).await;
+                let res = send_message(message, &controller, &tls).await;
                 if res.is_err() {
                     error!(
                         "send message {}",
@@ -108,8 +261,10 @@ async fn main() -> Result<(), MirrorError> {
                 referral_url_digest,
                 referral_size,
                 format,
+                working_dir,
             }) => {
                 let res = create_referral_manifest(
+                    working_dir,
                     name.to_string(),
                     referral_url_digest.to_string(),
                     *referral_size,
@@ -125,33 +280,82 @@ async fn main() -> Result<(), MirrorError> {
                     info!("created signed manifest for {}", name);
                 }
             }
-            Some(Commands::Keypair {}) => {
-                create_keypair().await?;
+            Some(Commands::Keypair { algorithm }) => {
+                create_keypair(&key_dir, *algorithm).await?;
                 info!("keypair successfully created")
             }
-            Some(Commands::Sign { artifact }) => {
+            Some(Commands::Sign {
+                artifact,
+                private_key,
+                working_dir,
+            }) => {
                 let name = artifact.split("/").last().unwrap();
-                let res = sign_artifact(name.to_string(), artifact.to_string()).await;
+                let res = match load_private_key(&private_key_source(&key_dir, private_key)) {
+                    Ok(key) => {
+                        sign_artifact(
+                            &key,
+                            &signature_path(&signature_dir(working_dir), name),
+                            name.to_string(),
+                            artifact.to_string(),
+                            None,
+                            None,
+                        )
+                        .await
+                    }
+                    Err(err) => Err(err),
+                };
                 if res.is_err() {
                     error!(
                         "{:#?}",
                         res.err().as_ref().unwrap().to_string().to_lowercase()
                     );
+                    process::exit(1);
                 }
                 info!("artifact {} successfully signed", name);
             }
-            Some(Commands::Verify { artifact }) => {
+            Some(Commands::Verify {
+                artifact,
+                public_key,
+                registry,
+                working_dir,
+            }) => {
                 let name = artifact.split("/").last().unwrap();
-                let res = verify_artifact(name.to_string(), artifact.to_string()).await;
-                if res.is_err() {
-                    error!(
-                        "{:#?}",
-                        res.as_ref().err().unwrap().to_string().to_lowercase()
-                    );
+                // an explicit public key is trusted instead of the trust policy
+                let policy = match public_key {
+                    Some(_) => Ok(TrustStore::new(single_key_policy(&public_key_source(
+                        &key_dir, public_key,
+                    )))),
+                    None => load_trust_policy(&trust_policy, &key_dir).await,
+                };
+                if policy.is_err() {
+                    error!("{}", policy.err().unwrap().to_string().to_lowercase());
+                    process::exit(1);
                 }
-                match res.as_ref().unwrap() {
-                    true => info!("artifact {} is trusted", name),
-                    false => warn!("artitact {} is not trusted", name),
+                let signature = read_signature(&signature_path(&signature_dir(working_dir), name));
+                let verification = policy.unwrap().evaluate(
+                    name,
+                    &registry.clone().unwrap_or_default(),
+                    artifact,
+                    signature.as_ref(),
+                );
+                match verification {
+                    Verification::Trusted(key) => {
+                        info!("artifact {} is trusted (key {})", name, key)
+                    }
+                    Verification::Unsigned => {
+                        info!(
+                            "artifact {} is not signed (not required by the trust policy)",
+                            name
+                        )
+                    }
+                    Verification::Missing => {
+                        error!("artifact {} is not trusted (not signed)", name);
+                        process::exit(1);
+                    }
+                    Verification::Untrusted(reason) => {
+                        error!("artifact {} is not trusted {}", name, reason);
+                        process::exit(1);
+                    }
                 }
             }
             Some(Commands::Start {
@@ -170,9 +374,17 @@ async fn main() -> Result<(), MirrorError> {
                     skip_tls_verify: Some(true),
                     ip: None,
                     subnet: None,
+                    registration: None,
+                    id: None,
+                    digests: None,
+                    insecure_allow_unsigned: None,
+                    reproducible: None,
+                    compression: None,
+                    private_key: None,
+                    api_token: api_token.clone(),
                 };
                 let message = serde_json::to_string(&api_params).unwrap();
-                let res = send_message(message, server_ip).await;
+                let res = send_message(message, &controller, &tls).await;
                 if res.is_err() {
                     error!(
                         "send message {}",
@@ -198,9 +410,17 @@ async fn main() -> Result<(), MirrorError> {
                     skip_tls_verify: Some(true),
                     ip: None,
                     subnet: None,
+                    registration: None,
+                    id: None,
+                    digests: None,
+                    insecure_allow_unsigned: None,
+                    reproducible: None,
+                    compression: None,
+                    private_key: None,
+                    api_token: api_token.clone(),
                 };
                 let message = serde_json::to_string(&api_params).unwrap();
-                let res = send_message(message, server_ip).await;
+                let res = send_message(message, &controller, &tls).await;
                 if res.is_err() {
                     error!(
                         "send message {}",
@@ -221,9 +441,17 @@ async fn main() -> Result<(), MirrorError> {
                     skip_tls_verify: Some(true),
                     ip: None,
                     subnet: None,
+                    registration: None,
+                    id: None,
+                    digests: None,
+                    insecure_allow_unsigned: None,
+                    reproducible: None,
+                    compression: None,
+                    private_key: None,
+                    api_token: api_token.clone(),
                 };
                 let message = serde_json::to_string(&api_params).unwrap();
-                let res = send_message(message, server_ip).await;
+                let res = send_message(message, &controller, &tls).await;
                 if res.is_err() {
                     error!(
                         "send message {}",
@@ -255,9 +483,17 @@ async fn main() -> Result<(), MirrorError> {
                     skip_tls_verify: None,
                     ip: Some(ip.to_string()),
                     subnet: Some(*subnet),
+                    registration: None,
+                    id: None,
+                    digests: None,
+                    insecure_allow_unsigned: None,
+                    reproducible: None,
+                    compression: None,
+                    private_key: None,
+                    api_token: api_token.clone(),
                 };
                 let message = serde_json::to_string(&api_params).unwrap();
-                let res = send_message(message, server_ip).await;
+                let res = send_message(message, &controller, &tls).await;
                 if res.is_err() {
                     error!(
                         "send message {}",
@@ -267,6 +503,101 @@ async fn main() -> Result<(), MirrorError> {
                     info!("list message sent");
                 }
             }
+            Some(Commands::Export {
+                config_file,
+                working_dir,
+                output,
+                service,
+                skip_tls_verify,
+            }) => {
+                let res =
+                    transfer::export(config_file, working_dir, output, service, *skip_tls_verify)
+                        .await;
+                if res.is_err() {
+                    error!("export {}", res.err().unwrap().to_string().to_lowercase());
+                    process::exit(1);
+                }
+            }
+            Some(Commands::Import {
+                config_file,
+                working_dir,
+                input,
+                service,
+                skip_tls_verify,
+            }) => {
+                let res =
+                    transfer::import(config_file, working_dir, input, service, *skip_tls_verify)
+                        .await;
+                if res.is_err() {
+                    error!("import {}", res.err().unwrap().to_string().to_lowercase());
+                    process::exit(1);
+                }
+            }
+            Some(Commands::Events { follow, json }) => {
+                let res = stream_events(&controller, &tls, *follow, *json, &api_token).await;
+                if res.is_err() {
+                    error!("events {}", res.err().unwrap().to_string().to_lowercase());
+                    process::exit(1);
+                }
+            }
+            Some(Commands::Jobs { command }) => {
+                let (request, id) = match command {
+                    JobsCommands::List {} => ("jobs_list", None),
+                    JobsCommands::Wait { id } => ("jobs_wait", Some(id.clone())),
+                    JobsCommands::Cancel { id } => ("jobs_cancel", Some(id.clone())),
+                };
+                let res = job_request(request, id, &controller, &tls, &api_token).await;
+                if res.is_err() {
+                    error!("jobs {}", res.err().unwrap().to_string().to_lowercase());
+                    process::exit(1);
+                }
+                let res = res.unwrap();
+                if let JobsCommands::List {} = command {
+                    for job in res.jobs.unwrap_or_default() {
+                        println!(
+                            "{} {:<8} {:<20} {:<10} {}",
+                            job.id,
+                            job.command,
+                            job.node,
+                            format!("{:?}", job.status),
+                            job.progress.unwrap_or_default()
+                        );
+                    }
+                } else {
+                    for job in res.jobs.clone().unwrap_or_default() {
+                        for result in job.results {
+                            info!("{} {} {}", result.node, result.status, result.text);
+                        }
+                    }
+                    if res.status == "KO" {
+                        error!("{}", res.text);
+                        process::exit(1);
+                    }
+                    info!("{}", res.text);
+                }
+            }
+            Some(Commands::Controller { command }) => match command {
+                ControllerCommands::Token { command } => match command {
+                    TokenCommands::Create { ttl, api } => {
+                        let res = match api {
+                            true => create_api_token(data_dir, *ttl).await,
+                            false => create_token(data_dir, *ttl).await,
+                        };
+                        if res.is_err() {
+                            error!(
+                                "create token {}",
+                                res.err().unwrap().to_string().to_lowercase()
+                            );
+                            process::exit(1);
+                        }
+                        match api {
+                            true => info!("api token created (valid for {} seconds)", ttl),
+                            false => info!("join token created (valid for {} seconds)", ttl),
+                        }
+                        println!("{}", res.unwrap());
+                    }
+                },
+            },
 
             None => {
                 error!("sub command not recognized, use --help to get list of cli options");
@@ -0,0 +1,358 @@
+use crate::api::schema::{APIParameters, Registration};
+use crate::common::utils::{unix_timestamp, write_private_file};
+use crate::node::token::{use_token, validate_token};
+use base64::prelude::*;
+use mirror_error::MirrorError;
+use mirror_utils::fs_handler;
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::sign::{Signer, Verifier};
+use openssl::x509::extension::{BasicConstraints, ExtendedKeyUsage, KeyUsage};
+use openssl::x509::{X509Builder, X509NameBuilder, X509};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::{LazyLock, Mutex};
+
+// maximum clock skew allowed between worker and controller (seconds)
+const MAX_SKEW: u64 = 300;
+
+// registrations ("node:timestamp") accepted within the last MAX_SKEW seconds
+static SEEN_REGISTRATIONS: LazyLock<Mutex<HashMap<String, u64>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn to_mirror_error(context: &str, err: impl ToString) -> MirrorError {
+    MirrorError::new(&format!("[{}] {}", context, err.to_string().to_lowercase()))
+}
+
+// load the controller certificate authority, create it on first use
+pub async fn load_or_create_ca(data_dir: String) -> Result<(X509, PKey<Private>), MirrorError> {
+    let pki_dir = format!("{}/pki", data_dir);
+    let ca_cert = format!("{}/ca.crt", pki_dir);
+    let ca_key = format!("{}/ca.key", pki_dir);
+    if Path::new(&ca_cert).exists() && Path::new(&ca_key).exists() {
+        let cert_pem = fs::read(ca_cert).map_err(|e| to_mirror_error("load_ca", e))?;
+        let key_pem = fs::read(ca_key).map_err(|e| to_mirror_error("load_ca", e))?;
+        let cert = X509::from_pem(&cert_pem).map_err(|e| to_mirror_error("load_ca", e))?;
+        let key =
+            PKey::private_key_from_pem(&key_pem).map_err(|e| to_mirror_error("load_ca", e))?;
+        return Ok((cert, key));
+    }
+    fs_handler(pki_dir, "create_dir", None).await?;
+    let (cert, key) = create_ca().map_err(|e| to_mirror_error("create_ca", e))?;
+    let key_pem = key
+        .private_key_to_pem_pkcs8()
+        .map_err(|e| to_mirror_error("create_ca", e))?;
+    write_private_file(ca_key, &key_pem).map_err(|e| to_mirror_error("create_ca", e))?;
+    let cert_pem = cert.to_pem().map_err(|e| to_mirror_error("create_ca", e))?;
+    fs::write(ca_cert, cert_pem).map_err(|e| to_mirror_error("create_ca", e))?;
+    Ok((cert, key))
+}
+
+fn create_ca() -> Result<(X509, PKey<Private>), openssl::error::ErrorStack> {
+    let key = PKey::from_rsa(Rsa::generate(2048)?)?;
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, "mpm-controller-ca")?;
+    let name = name.build();
+    let mut builder = X509Builder::new()?;
+    builder.set_version(2)?;
+    let serial = random_serial()?;
+    builder.set_serial_number(&serial)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(&key)?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(3650)?;
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+    builder.append_extension(BasicConstraints::new().critical().ca().build()?)?;
+    builder.append_extension(
+        KeyUsage::new()
+            .critical()
+            .key_cert_sign()
+            .crl_sign()
+            .build()?,
+    )?;
+    builder.sign(&key, MessageDigest::sha256())?;
+    Ok((builder.build(), key))
+}
+
+fn random_serial() -> Result<openssl::asn1::Asn1Integer, openssl::error::ErrorStack> {
+    let mut serial = BigNum::new()?;
+    serial.rand(128, MsbOption::MAYBE_ZERO, false)?;
+    serial.to_asn1_integer()
+}
+
+// issue a long lived client certificate for the worker public key
+fn issue_certificate(
+    ca_cert: &X509,
+    ca_key: &PKey<Private>,
+    node: &str,
+    public_key_pem: &str,
+) -> Result<String, openssl::error::ErrorStack> {
+    let public_key = PKey::public_key_from_pem(public_key_pem.as_bytes())?;
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, node)?;
+    let name = name.build();
+    let mut builder = X509Builder::new()?;
+    builder.set_version(2)?;
+    let serial = random_serial()?;
+    builder.set_serial_number(&serial)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(ca_cert.subject_name())?;
+    builder.set_pubkey(&public_key)?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(365)?;
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+    builder.append_extension(BasicConstraints::new().build()?)?;
+    builder.append_extension(KeyUsage::new().critical().digital_signature().build()?)?;
+    builder.append_extension(ExtendedKeyUsage::new().client_auth().build()?)?;
+    builder.sign(ca_key, MessageDigest::sha256())?;
+    let pem = builder.build().to_pem()?;
+    Ok(String::from_utf8_lossy(&pem).to_string())
+}
+
+// check the worker certificate was issued by the controller for this node and
+// that the worker holds the private key (signature over "node:timestamp")
+fn verify_certificate(
+    ca_key: &PKey<Private>,
+    node: &str,
+    registration: &Registration,
+) -> Result<(), MirrorError> {
+    let cert_pem = registration.certificate.as_ref().unwrap();
+    let cert = X509::from_pem(cert_pem.as_bytes()).map_err(|e| to_mirror_error("verify", e))?;
+    let issued = cert
+        .verify(ca_key)
+        .map_err(|e| to_mirror_error("verify", e))?;
+    if !issued {
+        return Err(MirrorError::new(
+            "certificate was not issued by this controller",
+        ));
+    }
+    let common_name = cert
+        .subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .and_then(|entry| entry.data().as_utf8().ok())
+        .map(|cn| cn.to_string());
+    if common_name.as_deref() != Some(node) {
+        return Err(MirrorError::new(&format!(
+            "certificate was not issued for node {}",
+            node
+        )));
+    }
+    let now = Asn1Time::days_from_now(0).map_err(|e| to_mirror_error("verify", e))?;
+    let expiry = cert
+        .not_after()
+        .compare(&now)
+        .map_err(|e| to_mirror_error("verify", e))?;
+    if expiry == Ordering::Less {
+        return Err(MirrorError::new("certificate has expired"));
+    }
+    let timestamp = registration.timestamp.unwrap_or(0);
+    if unix_timestamp().abs_diff(timestamp) > MAX_SKEW {
+        return Err(MirrorError::new("registration timestamp is out of range"));
+    }
+    let signature = BASE64_STANDARD
+        .decode(registration.signature.clone().unwrap_or_default())
+        .map_err(|e| to_mirror_error("verify", e))?;
+    let public_key = cert
+        .public_key()
+        .map_err(|e| to_mirror_error("verify", e))?;
+    let mut verifier = Verifier::new(MessageDigest::sha256(), &public_key)
+        .map_err(|e| to_mirror_error("verify", e))?;
+    verifier
+        .update(format!("{}:{}", node, timestamp).as_bytes())
+        .map_err(|e| to_mirror_error("verify", e))?;
+    let valid = verifier
+        .verify(&signature)
+        .map_err(|e| to_mirror_error("verify", e))?;
+    if !valid {
+        return Err(MirrorError::new("registration signature is invalid"));
+    }
+    check_replay(node, timestamp)
+}
+
+// a signed registration is only accepted once, older entries are outside
+// the allowed skew and rejected by the timestamp check anyway
+fn check_replay(node: &str, timestamp: u64) -> Result<(), MirrorError> {
+    let now = unix_timestamp();
+    let mut seen = SEEN_REGISTRATIONS.lock().unwrap();
+    seen.retain(|_, seen_at| now.abs_diff(*seen_at) <= MAX_SKEW);
+    if seen
+        .insert(format!("{}:{}", node, timestamp), timestamp)
+        .is_some()
+    {
+        return Err(MirrorError::new("registration has already been used"));
+    }
+    Ok(())
+}
+
+// controller side of a worker registration
+// returns a newly issued certificate (pem) when the worker enrolled with a join token
+pub async fn register(
+    data_dir: String,
+    params: &APIParameters,
+) -> Result<Option<String>, MirrorError> {
+    let registration = match params.registration.as_ref() {
+        Some(registration) => registration,
+        None => return Err(MirrorError::new("missing registration details")),
+    };
+    let (ca_cert, ca_key) = load_or_create_ca(data_dir.clone()).await?;
+    if registration.certificate.is_some() {
+        verify_certificate(&ca_key, &params.node, registration)?;
+        return Ok(None);
+    }
+    match (&registration.token, &registration.public_key) {
+        (Some(token), Some(public_key)) => {
+            validate_token(data_dir.clone(), token.clone())?;
+            let cert = issue_certificate(&ca_cert, &ca_key, &params.node, public_key)
+                .map_err(|e| to_mirror_error("issue_certificate", e))?;
+            use_token(data_dir, token.clone())?;
+            Ok(Some(cert))
+        }
+        _ => Err(MirrorError::new(
+            "worker must present a join token or a certificate",
+        )),
+    }
+}
+
+// load the worker private key, create it on first use
+fn load_or_create_worker_key(data_dir: &str) -> Result<PKey<Private>, MirrorError> {
+    let key_file = format!("{}/credentials/worker.key", data_dir);
+    if Path::new(&key_file).exists() {
+        let pem = fs::read(key_file).map_err(|e| to_mirror_error("worker_key", e))?;
+        return PKey::private_key_from_pem(&pem).map_err(|e| to_mirror_error("worker_key", e));
+    }
+    let rsa = Rsa::generate(2048).map_err(|e| to_mirror_error("worker_key", e))?;
+    let key = PKey::from_rsa(rsa).map_err(|e| to_mirror_error("worker_key", e))?;
+    let pem = key
+        .private_key_to_pem_pkcs8()
+        .map_err(|e| to_mirror_error("worker_key", e))?;
+    write_private_file(key_file, &pem).map_err(|e| to_mirror_error("worker_key", e))?;
+    Ok(key)
+}
+
+// worker side, build the registration details sent to the controller
+pub async fn registration(
+    data_dir: String,
+    node: String,
+    token: Option<String>,
+) -> Result<Registration, MirrorError> {
+    fs_handler(format!("{}/credentials", data_dir), "create_dir", None).await?;
+    let key = load_or_create_worker_key(&data_dir)?;
+    let cert_file = format!("{}/credentials/worker.crt", data_dir);
+    if Path::new(&cert_file).exists() {
+        let cert = fs::read_to_string(cert_file).map_err(|e| to_mirror_error("registration", e))?;
+        let timestamp = unix_timestamp();
+        let mut signer = Signer::new(MessageDigest::sha256(), &key)
+            .map_err(|e| to_mirror_error("registration", e))?;
+        signer
+            .update(format!("{}:{}", node, timestamp).as_bytes())
+            .map_err(|e| to_mirror_error("registration", e))?;
+        let signature = signer
+            .sign_to_vec()
+            .map_err(|e| to_mirror_error("registration", e))?;
+        return Ok(Registration {
+            token: None,
+            public_key: None,
+            certificate: Some(cert),
+            timestamp: Some(timestamp),
+            signature: Some(BASE64_STANDARD.encode(signature)),
+        });
+    }
+    if token.is_none() {
+        return Err(MirrorError::new(
+            "worker is not enrolled, a join token is required (use --join-token)",
+        ));
+    }
+    let public_key = key
+        .public_key_to_pem()
+        .map_err(|e| to_mirror_error("registration", e))?;
+    Ok(Registration {
+        token,
+        public_key: Some(String::from_utf8_lossy(&public_key).to_string()),
+        certificate: None,
+        timestamp: None,
+        signature: None,
+    })
+}
+
+// worker side, persist the certificate issued by the controller
+pub fn save_certificate(data_dir: String, cert: String) -> Result<(), MirrorError> {
+    let res = fs::write(format!("{}/credentials/worker.crt", data_dir), cert);
+    if res.is_err() {
+        return Err(MirrorError::new(&format!(
+            "[save_certificate] {}",
+            res.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registration_replay_rejected() {
+        let timestamp = unix_timestamp();
+        check_replay("worker-1", timestamp).unwrap();
+        assert!(check_replay("worker-1", timestamp).is_err());
+        check_replay("worker-2", timestamp).unwrap();
+        check_replay("worker-1", timestamp + 1).unwrap();
+    }
+
+    #[tokio::test]
+    async fn join_token_single_use() {
+        let data_dir = std::env::temp_dir()
+            .join(format!("mpm-credential-{}", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        fs::create_dir_all(format!("{}/credentials", data_dir)).unwrap();
+        let token = crate::node::token::create_token(data_dir.clone(), 900)
+            .await
+            .unwrap();
+        let public_key = load_or_create_worker_key(&data_dir)
+            .unwrap()
+            .public_key_to_pem()
+            .unwrap();
+        let registration = Registration {
+            token: Some(token),
+            public_key: Some(String::from_utf8_lossy(&public_key).to_string()),
+            certificate: None,
+            timestamp: None,
+            signature: None,
+        };
+        let params = APIParameters {
+            command: "register".to_string(),
+            node: "worker-1".to_string(),
+            service: "".to_string(),
+            config_file: None,
+            working_dir: None,
+            from_registry: None,
+            skip_tls_verify: None,
+            ip: None,
+            subnet: None,
+            registration: Some(registration),
+            id: None,
+            digests: None,
+            insecure_allow_unsigned: None,
+            reproducible: None,
+            compression: None,
+            private_key: None,
+            api_token: None,
+        };
+        let first = register(data_dir.clone(), &params).await;
+        let second = register(data_dir.clone(), &params).await;
+        let _ = fs::remove_dir_all(&data_dir);
+        assert!(first.unwrap().is_some());
+        assert!(second.is_err());
+    }
+}
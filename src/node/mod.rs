@@ -0,0 +1,3 @@
+pub mod credential;
+pub mod registry;
+pub mod token;
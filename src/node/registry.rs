@@ -0,0 +1,222 @@
+use crate::api::schema::{DeployedService, EventKind, NodeInfo, NodeStatus};
+use crate::common::utils::unix_timestamp;
+use crate::event::bus::{new_event, EventBus};
+use crate::store::state::StateStore;
+use custom_logger::*;
+use std::collections::HashMap;
+
+// interval (seconds) between worker heartbeats
+pub const HEARTBEAT_INTERVAL: u64 = 10;
+// number of missed heartbeats before a node is marked NotReady
+pub const MISSED_HEARTBEATS: u64 = 3;
+
+// map a reported service status to an event, a service started again after
+// it exited or was stopped is reported as restarted
+fn service_event(status: &str, previous: Option<&str>) -> Option<EventKind> {
+    match (status, previous) {
+        ("staged", _) => Some(EventKind::ServiceStaged),
+        ("running", Some("exited")) | ("running", Some("stopped")) => {
+            Some(EventKind::ServiceRestarted)
+        }
+        ("running", _) => Some(EventKind::ServiceStarted),
+        ("stopped", _) => Some(EventKind::ServiceStopped),
+        ("failed", _) => Some(EventKind::ServiceFailed),
+        ("exited", _) => Some(EventKind::ServiceExited),
+        _ => None,
+    }
+}
+
+struct Entry {
+    info: NodeInfo,
+    // identifies the connection the node registered on
+    session: u64,
+}
+
+// controller view of all registered workers and their deployed services
+// status changes are persisted to the state store and published as events,
+// heartbeats are kept in memory
+pub struct NodeRegistry {
+    nodes: HashMap<String, Entry>,
+    sessions: u64,
+    store: StateStore,
+    events: EventBus,
+}
+
+impl NodeRegistry {
+    // nodes known from a previous run stay NotReady until they reconnect
+    pub fn new(store: StateStore, events: EventBus) -> Self {
+        let nodes = store
+            .state
+            .nodes
+            .iter()
+            .map(|info| {
+                let mut info = info.clone();
+                info.status = NodeStatus::NotReady;
+                (info.name.clone(), Entry { info, session: 0 })
+            })
+            .collect();
+        Self {
+            nodes,
+            sessions: 0,
+            store,
+            events,
+        }
+    }
+
+    fn persist(&mut self) {
+        let mut nodes = self
+            .nodes
+            .values()
+            .map(|entry| entry.info.clone())
+            .collect::<Vec<NodeInfo>>();
+        nodes.sort_by(|a, b| a.name.cmp(&b.name));
+        self.store.state.nodes = nodes;
+        if let Err(err) = self.store.save() {
+            error!("{}", err.to_string().to_lowercase());
+        }
+    }
+
+    // mark the node as ready, returns the session id of this connection
+    pub fn register(&mut self, node: &str) -> u64 {
+        self.sessions += 1;
+        let entry = Entry {
+            info: NodeInfo {
+                name: node.to_string(),
+                status: NodeStatus::Ready,
+                last_seen: unix_timestamp(),
+            },
+            session: self.sessions,
+        };
+        self.nodes.insert(node.to_string(), entry);
+        self.persist();
+        self.events
+            .publish(new_event(EventKind::NodeJoined, node, None, None));
+        self.sessions
+    }
+
+    pub fn heartbeat(&mut self, node: &str) {
+        if let Some(entry) = self.nodes.get_mut(node) {
+            entry.info.last_seen = unix_timestamp();
+            if entry.info.status != NodeStatus::Ready {
+                entry.info.status = NodeStatus::Ready;
+                self.persist();
+            }
+        }
+    }
+
+    // the connection was closed, ignored if the node already reconnected on a new session
+    pub fn disconnect(&mut self, node: &str, session: u64) {
+        if let Some(entry) = self.nodes.get_mut(node) {
+            if entry.session == session {
+                entry.info.status = NodeStatus::NotReady;
+                self.persist();
+                self.events.publish(new_event(
+                    EventKind::NodeLeft,
+                    node,
+                    None,
+                    Some("connection closed".to_string()),
+                ));
+            }
+        }
+    }
+
+    // mark nodes that missed their heartbeats as NotReady, returns the affected nodes
+    pub fn expire(&mut self) -> Vec<String> {
+        let deadline = unix_timestamp().saturating_sub(HEARTBEAT_INTERVAL * MISSED_HEARTBEATS);
+        let mut expired = vec![];
+        for entry in self.nodes.values_mut() {
+            if entry.info.status == NodeStatus::Ready && entry.info.last_seen < deadline {
+                entry.info.status = NodeStatus::NotReady;
+                expired.push(entry.info.name.clone());
+            }
+        }
+        if !expired.is_empty() {
+            self.persist();
+        }
+        for node in expired.iter() {
+            self.events.publish(new_event(
+                EventKind::NodeLeft,
+                node,
+                None,
+                Some("missed heartbeats".to_string()),
+            ));
+        }
+        expired
+    }
+
+    // record the outcome of a command reported by a worker
+    // version and digest are only replaced when the worker reports them
+    pub fn record_services(
+        &mut self,
+        node: &str,
+        services: Vec<DeployedService>,
+        message: Option<String>,
+    ) {
+        for mut service in services {
+            service.node = node.to_string();
+            service.updated = unix_timestamp();
+            let existing = self
+                .store
+                .state
+                .services
+                .iter_mut()
+                .find(|s| s.node == service.node && s.name == service.name);
+            let previous = existing.as_ref().map(|s| s.status.clone());
+            if let Some(kind) = service_event(&service.status, previous.as_deref()) {
+                self.events
+                    .publish(new_event(kind, node, Some(&service.name), message.clone()));
+            }
+            match existing {
+                Some(existing) => {
+                    existing.status = service.status;
+                    existing.updated = service.updated;
+                    if service.version.is_some() {
+                        existing.version = service.version;
+                    }
+                    if service.digest.is_some() {
+                        existing.digest = service.digest;
+                    }
+                }
+                None => self.store.state.services.push(service),
+            }
+        }
+        self.persist();
+    }
+
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        let mut nodes = self
+            .nodes
+            .values()
+            .map(|entry| entry.info.clone())
+            .collect::<Vec<NodeInfo>>();
+        nodes.sort_by(|a, b| a.name.cmp(&b.name));
+        nodes
+    }
+
+    pub fn all_services(&self) -> Vec<DeployedService> {
+        self.store.state.services.clone()
+    }
+
+    // nodes matching the node name (or all) that can currently receive commands
+    pub fn ready_nodes(&self, node: &str) -> Vec<String> {
+        let mut nodes = self
+            .nodes
+            .values()
+            .filter(|entry| entry.info.status == NodeStatus::Ready)
+            .filter(|entry| node == "all" || entry.info.name == node)
+            .map(|entry| entry.info.name.clone())
+            .collect::<Vec<String>>();
+        nodes.sort();
+        nodes
+    }
+
+    pub fn services(&self, node: &str) -> Vec<DeployedService> {
+        self.store
+            .state
+            .services
+            .iter()
+            .filter(|service| service.node == node)
+            .cloned()
+            .collect()
+    }
+}
@@ -0,0 +1,100 @@
+use crate::api::schema::JoinToken;
+use crate::common::utils::{unix_timestamp, write_private_file};
+use mirror_error::MirrorError;
+use mirror_utils::fs_handler;
+use rand::{
+    distr::{Alphanumeric, SampleString},
+    rng,
+};
+use sha256::digest;
+use std::fs;
+
+// join tokens enroll workers, api tokens authorize http api requests (bearer)
+const JOIN_TOKENS: &str = "tokens";
+const API_TOKENS: &str = "api-tokens";
+
+// create a join token in the form <id>.<secret>
+// only the sha256 hash of the secret is persisted on the controller
+pub async fn create_token(data_dir: String, ttl: u64) -> Result<String, MirrorError> {
+    store_token(format!("{}/{}", data_dir, JOIN_TOKENS), ttl).await
+}
+
+// create a token for the controller http api, stored apart from the join tokens
+pub async fn create_api_token(data_dir: String, ttl: u64) -> Result<String, MirrorError> {
+    store_token(format!("{}/{}", data_dir, API_TOKENS), ttl).await
+}
+
+async fn store_token(tokens_dir: String, ttl: u64) -> Result<String, MirrorError> {
+    fs_handler(tokens_dir.clone(), "create_dir", None).await?;
+    let id = Alphanumeric.sample_string(&mut rng(), 6).to_lowercase();
+    let secret = Alphanumeric.sample_string(&mut rng(), 16).to_lowercase();
+    let join_token = JoinToken {
+        id: id.clone(),
+        hash: digest(secret.as_bytes()),
+        expires: unix_timestamp() + ttl,
+    };
+    let json = serde_json::to_string(&join_token).unwrap();
+    let res = write_private_file(format!("{}/{}.json", tokens_dir, id), json.as_bytes());
+    if res.is_err() {
+        return Err(MirrorError::new(&format!(
+            "[create_token] writing token {}",
+            res.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    Ok(format!("{}.{}", id, secret))
+}
+
+// validate a join token presented by a worker
+// tokens are single use (see use_token), expired tokens are removed
+pub fn validate_token(data_dir: String, token: String) -> Result<(), MirrorError> {
+    check_token(
+        &format!("{}/{}", data_dir, JOIN_TOKENS),
+        &token,
+        "join token",
+    )
+}
+
+// mark a join token as used once a certificate was issued for it,
+// only one of two concurrent enrollments with the same token can remove it
+pub fn use_token(data_dir: String, token: String) -> Result<(), MirrorError> {
+    let id = token.split_once('.').map(|(id, _)| id).unwrap_or_default();
+    let res = fs::remove_file(format!("{}/{}/{}.json", data_dir, JOIN_TOKENS, id));
+    if res.is_err() {
+        return Err(MirrorError::new("join token has already been used"));
+    }
+    Ok(())
+}
+
+// validate the bearer token of an http api request
+pub fn validate_api_token(data_dir: &str, token: &str) -> Result<(), MirrorError> {
+    check_token(&format!("{}/{}", data_dir, API_TOKENS), token, "api token")
+}
+
+fn check_token(tokens_dir: &str, token: &str, kind: &str) -> Result<(), MirrorError> {
+    let (id, secret) = match token.split_once('.') {
+        Some((id, secret)) if id.chars().all(|c| c.is_ascii_alphanumeric()) => (id, secret),
+        _ => return Err(MirrorError::new(&format!("{} is malformed", kind))),
+    };
+    let token_file = format!("{}/{}.json", tokens_dir, id);
+    let res = fs::read_to_string(token_file.clone());
+    if res.is_err() {
+        return Err(MirrorError::new(&format!("{} not found", kind)));
+    }
+    let res_json = serde_json::from_str::<JoinToken>(&res.unwrap());
+    if res_json.is_err() {
+        return Err(MirrorError::new(&format!(
+            "parsing {} {}",
+            kind,
+            res_json.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    let join_token = res_json.unwrap();
+    if join_token.expires < unix_timestamp() {
+        let _ = fs::remove_file(token_file);
+        return Err(MirrorError::new(&format!("{} has expired", kind)));
+    }
+    if join_token.hash != digest(secret.as_bytes()) {
+        return Err(MirrorError::new(&format!("{} is invalid", kind)));
+    }
+    Ok(())
+}
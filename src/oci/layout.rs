@@ -0,0 +1,213 @@
+use crate::api::schema::{Layer, Manifest, OCIIndex};
+use crate::oci::reference::valid_digest;
+use crate::oci::registry::OCI_MANIFEST;
+use mirror_error::MirrorError;
+use mirror_utils::fs_handler;
+use sha2::{Digest, Sha256};
+use sha256::digest;
+use std::fs::File;
+use std::io;
+use tar::Archive;
+
+// marks a directory as an oci image layout (required by the image layout spec)
+pub async fn write_layout_marker(dir: &str) -> Result<(), MirrorError> {
+    fs_handler(
+        format!("{}/oci-layout", dir),
+        "write",
+        Some(r#"{"imageLayoutVersion":"1.0.0"}"#.to_string()),
+    )
+    .await?;
+    Ok(())
+}
+
+// path of a blob in the layout (blobs/sha256/<hex>), digests come from untrusted
+// manifests and are checked before they become a path
+pub fn blob_path(dir: &str, blob_digest: &str) -> Result<String, MirrorError> {
+    match blob_digest.split_once(":") {
+        Some(("sha256", hex)) if valid_digest(blob_digest) => {
+            Ok(format!("{}/blobs/sha256/{}", dir, hex))
+        }
+        _ => Err(MirrorError::new(&format!(
+            "[layout] invalid digest {}",
+            blob_digest
+        ))),
+    }
+}
+
+// store a manifest (or index) as a blob, returns its descriptor
+pub async fn store_manifest(dir: &str, manifest: &str) -> Result<Layer, MirrorError> {
+    let hash = format!("sha256:{}", digest(manifest));
+    fs_handler(blob_path(dir, &hash)?, "write", Some(manifest.to_string())).await?;
+    Ok(Layer {
+        media_type: manifest_media_type(manifest),
+        digest: hash,
+        size: manifest.len() as i64,
+        annotations: None,
+        platform: None,
+        artifact_type: None,
+    })
+}
+
+// media type declared in a manifest, image manifest when not set
+pub fn manifest_media_type(manifest: &str) -> String {
+    serde_json::from_str::<serde_json::Value>(manifest)
+        .ok()
+        .and_then(|value| value["mediaType"].as_str().map(|s| s.to_string()))
+        .unwrap_or(OCI_MANIFEST.to_string())
+}
+
+// manifests and blobs referenced from a manifest or an image index
+pub fn references(manifest: &str) -> Result<(Vec<Layer>, Vec<Layer>), MirrorError> {
+    if let Ok(index) = serde_json::from_str::<OCIIndex>(manifest) {
+        check_digests(&index.manifests)?;
+        return Ok((index.manifests, vec![]));
+    }
+    let res = serde_json::from_str::<Manifest>(manifest);
+    if res.is_err() {
+        return Err(MirrorError::new(&format!(
+            "[layout] parsing manifest {}",
+            res.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    let manifest = res.unwrap();
+    let mut blobs = vec![];
+    blobs.extend(manifest.config);
+    blobs.extend(manifest.layers.unwrap_or_default());
+    check_digests(&blobs)?;
+    Ok((vec![], blobs))
+}
+
+// every digest referenced from an untrusted manifest must be well formed
+fn check_digests(descriptors: &[Layer]) -> Result<(), MirrorError> {
+    match descriptors.iter().find(|d| !valid_digest(&d.digest)) {
+        Some(descriptor) => Err(MirrorError::new(&format!(
+            "[layout] invalid digest {}",
+            descriptor.digest
+        ))),
+        None => Ok(()),
+    }
+}
+
+pub async fn read_index(dir: &str) -> Result<OCIIndex, MirrorError> {
+    let data = fs_handler(format!("{}/index.json", dir), "read", None).await?;
+    let res = serde_json::from_str::<OCIIndex>(&data);
+    if res.is_err() {
+        return Err(MirrorError::new(&format!(
+            "[layout] parsing {}/index.json {}",
+            dir,
+            res.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    Ok(res.unwrap())
+}
+
+// tar a layout directory, entries are relative to the layout root
+pub fn archive_layout(dir: &str, file: &str) -> Result<(), MirrorError> {
+    let res = File::create(file);
+    if res.is_err() {
+        return Err(MirrorError::new(&format!(
+            "[layout] creating archive {} {}",
+            file,
+            res.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    let mut builder = tar::Builder::new(res.unwrap());
+    for entry in ["oci-layout", "index.json", "blobs"] {
+        let path = format!("{}/{}", dir, entry);
+        let res = match entry {
+            "blobs" => builder.append_dir_all(entry, &path),
+            _ => builder.append_path_with_name(&path, entry),
+        };
+        if res.is_err() {
+            return Err(MirrorError::new(&format!(
+                "[layout] archiving {} {}",
+                path,
+                res.err().unwrap().to_string().to_lowercase()
+            )));
+        }
+    }
+    let res = builder.finish();
+    if res.is_err() {
+        return Err(MirrorError::new(&format!(
+            "[layout] writing archive {} {}",
+            file,
+            res.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    Ok(())
+}
+
+// untar a layout archive, the oci-layout marker must be present
+pub fn unpack_layout(file: &str, dir: &str) -> Result<(), MirrorError> {
+    let res = File::open(file);
+    if res.is_err() {
+        return Err(MirrorError::new(&format!(
+            "[layout] opening archive {} {}",
+            file,
+            res.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    let res = Archive::new(res.unwrap()).unpack(dir);
+    if res.is_err() {
+        return Err(MirrorError::new(&format!(
+            "[layout] unpacking archive {} {}",
+            file,
+            res.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    if !std::path::Path::new(&format!("{}/oci-layout", dir)).exists() {
+        return Err(MirrorError::new(&format!(
+            "[layout] {} is not an oci layout (missing oci-layout)",
+            file
+        )));
+    }
+    Ok(())
+}
+
+// check a manifest against the digest it was referenced by
+pub fn verify_manifest(manifest: &str, expected: &str) -> Result<(), MirrorError> {
+    let actual = format!("sha256:{}", digest(manifest));
+    if actual != expected {
+        return Err(MirrorError::new(&format!(
+            "manifest digest mismatch expected {} got {}",
+            expected, actual
+        )));
+    }
+    Ok(())
+}
+
+// check the sha256 and size of a blob against its descriptor
+pub fn verify_blob(path: &str, descriptor: &Layer) -> Result<(), MirrorError> {
+    let file = File::open(path);
+    if file.is_err() {
+        return Err(MirrorError::new(&format!(
+            "opening blob {} {}",
+            descriptor.digest,
+            file.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    let mut hasher = Sha256::new();
+    let res = io::copy(&mut file.unwrap(), &mut hasher);
+    if res.is_err() {
+        return Err(MirrorError::new(&format!(
+            "reading blob {} {}",
+            descriptor.digest,
+            res.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    let size = res.unwrap() as i64;
+    if size != descriptor.size {
+        return Err(MirrorError::new(&format!(
+            "blob {} size mismatch expected {} got {}",
+            descriptor.digest, descriptor.size, size
+        )));
+    }
+    let actual = format!("sha256:{:x}", hasher.finalize());
+    if actual != descriptor.digest {
+        return Err(MirrorError::new(&format!(
+            "blob digest mismatch expected {} got {}",
+            descriptor.digest, actual
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,3 @@
+pub mod layout;
+pub mod reference;
+pub mod registry;
@@ -0,0 +1,222 @@
+use mirror_error::MirrorError;
+
+const DEFAULT_REGISTRY: &str = "docker.io";
+const DEFAULT_TAG: &str = "latest";
+
+// an image reference [registry[:port]/]repository[:tag][@digest]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reference {
+    pub registry: String,
+    pub repository: String,
+    pub tag: Option<String>,
+    pub digest: Option<String>,
+}
+
+impl Reference {
+    // tag or digest used to pull the manifest (the digest wins when both are set)
+    pub fn version(&self) -> String {
+        self.digest
+            .clone()
+            .or(self.tag.clone())
+            .unwrap_or(DEFAULT_TAG.to_string())
+    }
+
+    // host serving the registry api (docker hub is served from registry-1.docker.io)
+    pub fn api_host(&self) -> String {
+        match self.registry.as_str() {
+            DEFAULT_REGISTRY => "registry-1.docker.io".to_string(),
+            registry => registry.to_string(),
+        }
+    }
+}
+
+fn invalid(value: &str, reason: &str) -> MirrorError {
+    MirrorError::new(&format!("invalid image reference {} ({})", value, reason))
+}
+
+// path components are lowercase alphanumerics separated by '.', '_', '__' or '-'
+fn valid_component(component: &str) -> bool {
+    let bytes = component.as_bytes();
+    !component.is_empty()
+        && bytes[0].is_ascii_alphanumeric()
+        && bytes[bytes.len() - 1].is_ascii_alphanumeric()
+        && component
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "._-".contains(c))
+}
+
+fn valid_tag(tag: &str) -> bool {
+    !tag.is_empty()
+        && tag.len() <= 128
+        && !tag.starts_with(['.', '-'])
+        && tag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+}
+
+// digests end up in blob paths, only lowercase hex is accepted,
+// sha256 is the only algorithm manifests and blobs are verified with
+pub fn valid_digest(digest: &str) -> bool {
+    match digest.split_once(":") {
+        Some(("sha256", hex)) => {
+            hex.len() == 64 && hex.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+        }
+        _ => false,
+    }
+}
+
+// a registry is a host with an optional numeric port (ipv6 hosts in brackets)
+fn valid_registry(registry: &str) -> bool {
+    let (host, port) = match registry.rsplit_once(':') {
+        Some((host, port)) if !registry.ends_with(']') => (host, Some(port)),
+        _ => (registry, None),
+    };
+    !host.is_empty()
+        && !host.starts_with(['.', '-'])
+        && port.map_or(true, |port| {
+            !port.is_empty() && port.chars().all(|c| c.is_ascii_digit())
+        })
+}
+
+// the first component is a registry when it has a '.' or a port, or is localhost
+fn is_registry(component: &str) -> bool {
+    component.contains('.') || component.contains(':') || component == "localhost"
+}
+
+// parse a reference, a missing registry defaults to docker.io (library/ for single names)
+pub fn parse_reference(value: &str) -> Result<Reference, MirrorError> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Err(invalid(value, "empty"));
+    }
+    let (remainder, digest) = match value.split_once("@") {
+        Some((remainder, digest)) => {
+            if !valid_digest(digest) {
+                return Err(invalid(value, "malformed digest (sha256 only)"));
+            }
+            (remainder, Some(digest.to_string()))
+        }
+        None => (value, None),
+    };
+    // a ':' after the last '/' separates the tag (a ':' before it is a port)
+    let last_slash = remainder.rfind('/').map_or(0, |i| i + 1);
+    let (name, tag) = match remainder[last_slash..].split_once(":") {
+        Some((_, tag)) => {
+            if !valid_tag(tag) {
+                return Err(invalid(value, "malformed tag"));
+            }
+            let end = last_slash + remainder[last_slash..].find(':').unwrap();
+            (&remainder[..end], Some(tag.to_string()))
+        }
+        None => (remainder, None),
+    };
+    let (registry, repository) = match name.split_once("/") {
+        Some((first, rest)) if is_registry(first) => (first.to_string(), rest.to_string()),
+        _ => (DEFAULT_REGISTRY.to_string(), name.to_string()),
+    };
+    if !valid_registry(&registry) {
+        return Err(invalid(value, "malformed registry"));
+    }
+    let repository = match (registry.as_str(), repository.contains('/')) {
+        (DEFAULT_REGISTRY, false) => format!("library/{}", repository),
+        _ => repository,
+    };
+    if !repository.split('/').all(valid_component) {
+        return Err(invalid(value, "malformed repository"));
+    }
+    Ok(Reference {
+        registry,
+        repository,
+        tag,
+        digest,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "sha256:4f1c1e9d7a1f7b4a1c3e5d6f7a8b9c0d1e2f3a4b5c6d7e8f9a0b1c2d3e4f5a6b";
+
+    #[test]
+    fn registry_with_port() {
+        let reference = parse_reference("192.168.1.27:5000/init/convey:v0.1.0").unwrap();
+        assert_eq!(reference.registry, "192.168.1.27:5000");
+        assert_eq!(reference.repository, "init/convey");
+        assert_eq!(reference.tag.as_deref(), Some("v0.1.0"));
+        assert_eq!(reference.version(), "v0.1.0");
+    }
+
+    #[test]
+    fn port_without_tag() {
+        let reference = parse_reference("localhost:5000/convey").unwrap();
+        assert_eq!(reference.registry, "localhost:5000");
+        assert_eq!(reference.repository, "convey");
+        assert_eq!(reference.tag, None);
+        assert_eq!(reference.version(), DEFAULT_TAG);
+    }
+
+    #[test]
+    fn ipv6_registry() {
+        let reference = parse_reference("[::1]:5000/convey:v1").unwrap();
+        assert_eq!(reference.registry, "[::1]:5000");
+        assert_eq!(reference.repository, "convey");
+    }
+
+    #[test]
+    fn nested_namespaces() {
+        let reference = parse_reference("quay.io/acme/team/tools/convey:1.2").unwrap();
+        assert_eq!(reference.registry, "quay.io");
+        assert_eq!(reference.repository, "acme/team/tools/convey");
+        assert_eq!(reference.tag.as_deref(), Some("1.2"));
+    }
+
+    #[test]
+    fn docker_hub_defaults() {
+        let reference = parse_reference("convey").unwrap();
+        assert_eq!(reference.registry, "docker.io");
+        assert_eq!(reference.repository, "library/convey");
+        assert_eq!(reference.api_host(), "registry-1.docker.io");
+        let reference = parse_reference("lzuccarelli/convey:v1").unwrap();
+        assert_eq!(reference.registry, "docker.io");
+        assert_eq!(reference.repository, "lzuccarelli/convey");
+    }
+
+    #[test]
+    fn digest_wins_over_tag() {
+        let value = format!("quay.io/acme/convey:v1@{}", DIGEST);
+        let reference = parse_reference(&value).unwrap();
+        assert_eq!(reference.tag.as_deref(), Some("v1"));
+        assert_eq!(reference.digest.as_deref(), Some(DIGEST));
+        assert_eq!(reference.version(), DIGEST);
+    }
+
+    #[test]
+    fn malformed_references() {
+        for value in [
+            "",
+            "quay.io/Acme/convey",
+            "quay.io/acme/convey:",
+            "quay.io/acme/convey:-v1",
+            "quay.io/acme//convey",
+            ":5000/convey",
+            "quay.io:port/convey",
+            "quay.io/acme/convey@sha256:1234",
+            "quay.io/acme/convey@sha256:../../etc/passwd",
+        ] {
+            assert!(
+                parse_reference(value).is_err(),
+                "{} should be rejected",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn digest_algorithms() {
+        assert!(valid_digest(DIGEST));
+        assert!(!valid_digest(&DIGEST.to_uppercase()));
+        assert!(!valid_digest(&format!("sha512:{}", "a".repeat(128))));
+        assert!(!valid_digest("md5:d41d8cd98f00b204e9800998ecf8427e"));
+    }
+}
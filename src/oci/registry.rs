@@ -0,0 +1,563 @@
+use crate::api::schema::{Layer, OCIIndex};
+use crate::job::progress::ProgressReporter;
+use custom_logger::*;
+use mirror_error::MirrorError;
+use reqwest::header::{
+    ACCEPT, AUTHORIZATION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, LOCATION, RANGE,
+};
+use reqwest::{RequestBuilder, StatusCode};
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::time::Duration;
+use tokio::time::sleep;
+
+pub const OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+pub const OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+pub const OCI_LAYER_TAR: &str = "application/vnd.oci.image.layer.v1.tar";
+pub const OCI_LAYER_GZIP: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+pub const OCI_LAYER_ZSTD: &str = "application/vnd.oci.image.layer.v1.tar+zstd";
+
+// manifest types accepted when pulling (a tag can point to an index or a manifest)
+const ACCEPTED_MANIFESTS: &str = "application/vnd.oci.image.index.v1+json, \
+    application/vnd.oci.image.manifest.v1+json, \
+    application/vnd.docker.distribution.manifest.list.v2+json, \
+    application/vnd.docker.distribution.manifest.v2+json";
+
+// bytes downloaded between two progress reports
+const PROGRESS_BYTES: u64 = 4 * 1024 * 1024;
+
+// base url of the repository api, plain http when tls verification is skipped
+pub fn repository_url(registry: &str, repository: &str, skip_tls_verify: bool) -> String {
+    match skip_tls_verify {
+        true => format!("http://{}/v2/{}", registry, repository),
+        false => format!("https://{}/v2/{}", registry, repository),
+    }
+}
+
+fn with_token(request: RequestBuilder, token: &str) -> RequestBuilder {
+    match token.is_empty() {
+        true => request,
+        false => request.header(AUTHORIZATION, format!("Bearer {}", token)),
+    }
+}
+
+// pull a manifest or an image index by tag or digest
+pub async fn get_manifest(url: &str, token: &str) -> Result<String, MirrorError> {
+    let manifest = find_manifest(url, token).await?;
+    if manifest.is_none() {
+        return Err(MirrorError::new(&format!(
+            "[get_manifest] {} returned {}",
+            url,
+            StatusCode::NOT_FOUND
+        )));
+    }
+    Ok(manifest.unwrap())
+}
+
+// pull a manifest or an image index, returns none when it does not exist
+pub async fn find_manifest(url: &str, token: &str) -> Result<Option<String>, MirrorError> {
+    let client = reqwest::Client::new();
+    let request = with_token(client.get(url).header(ACCEPT, ACCEPTED_MANIFESTS), token);
+    let res = request.send().await;
+    if res.is_err() {
+        return Err(MirrorError::new(&format!(
+            "[get_manifest] {} {}",
+            url,
+            res.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    let res = res.unwrap();
+    if res.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if !res.status().is_success() {
+        return Err(MirrorError::new(&format!(
+            "[get_manifest] {} returned {}",
+            url,
+            res.status()
+        )));
+    }
+    let body = res.text().await;
+    if body.is_err() {
+        return Err(MirrorError::new(&format!(
+            "[get_manifest] reading body {}",
+            body.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    Ok(Some(body.unwrap()))
+}
+
+// response header of registries that processed the subject of a pushed manifest
+const OCI_SUBJECT: &str = "OCI-Subject";
+
+// push a manifest or an image index by tag or digest
+pub async fn put_manifest(
+    url: &str,
+    token: &str,
+    media_type: &str,
+    manifest: String,
+) -> Result<(), MirrorError> {
+    send_manifest(url, token, media_type, manifest).await?;
+    Ok(())
+}
+
+// push a manifest, returns the OCI-Subject header of the response (set by registries
+// supporting the referrers api when the manifest has a subject)
+async fn send_manifest(
+    url: &str,
+    token: &str,
+    media_type: &str,
+    manifest: String,
+) -> Result<Option<String>, MirrorError> {
+    let client = reqwest::Client::new();
+    let request = with_token(
+        client
+            .put(url)
+            .header(CONTENT_TYPE, media_type)
+            .body(manifest),
+        token,
+    );
+    let res = request.send().await;
+    if res.is_err() {
+        return Err(MirrorError::new(&format!(
+            "[put_manifest] {} {}",
+            url,
+            res.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    let res = res.unwrap();
+    if !res.status().is_success() {
+        return Err(MirrorError::new(&format!(
+            "[put_manifest] {} returned {}",
+            url,
+            res.status()
+        )));
+    }
+    let subject = res
+        .headers()
+        .get(OCI_SUBJECT)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+    Ok(subject)
+}
+
+// download a blob to a file, the bytes received so far are reported every
+// PROGRESS_BYTES and the download stops when the job is cancelled
+pub async fn get_blob(
+    url: &str,
+    token: &str,
+    path: &str,
+    progress: &ProgressReporter,
+    service: &str,
+) -> Result<u64, MirrorError> {
+    let client = reqwest::Client::new();
+    let res = with_token(client.get(url), token).send().await;
+    if res.is_err() {
+        return Err(MirrorError::new(&format!(
+            "[get_blob] {} {}",
+            url,
+            res.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    let mut res = res.unwrap();
+    if !res.status().is_success() {
+        return Err(MirrorError::new(&format!(
+            "[get_blob] {} returned {}",
+            url,
+            res.status()
+        )));
+    }
+    let file = File::create(path);
+    if file.is_err() {
+        return Err(MirrorError::new(&format!(
+            "[get_blob] creating {} {}",
+            path,
+            file.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    let mut file = file.unwrap();
+    let blob = url.rsplit('/').next().unwrap_or(url);
+    let mut size = 0;
+    let mut reported = 0;
+    loop {
+        progress.check_cancelled()?;
+        let chunk = res.chunk().await;
+        if chunk.is_err() {
+            return Err(MirrorError::new(&format!(
+                "[get_blob] reading {} {}",
+                url,
+                chunk.err().unwrap().to_string().to_lowercase()
+            )));
+        }
+        match chunk.unwrap() {
+            Some(bytes) => {
+                let res = file.write_all(&bytes);
+                if res.is_err() {
+                    return Err(MirrorError::new(&format!(
+                        "[get_blob] writing {} {}",
+                        path,
+                        res.err().unwrap().to_string().to_lowercase()
+                    )));
+                }
+                size += bytes.len() as u64;
+                if size - reported >= PROGRESS_BYTES {
+                    reported = size;
+                    progress
+                        .report(service, &format!("downloading blob {}", blob), Some(size))
+                        .await;
+                }
+            }
+            None => break,
+        }
+    }
+    Ok(size)
+}
+
+// list the referrers of a manifest (oci 1.1 referrers api),
+// returns none when the registry does not support the api
+pub async fn get_referrers(url: &str, token: &str) -> Result<Option<OCIIndex>, MirrorError> {
+    let client = reqwest::Client::new();
+    let request = with_token(client.get(url).header(ACCEPT, OCI_INDEX), token);
+    let res = request.send().await;
+    if res.is_err() {
+        return Err(MirrorError::new(&format!(
+            "[get_referrers] {} {}",
+            url,
+            res.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    let res = res.unwrap();
+    if res.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if !res.status().is_success() {
+        return Err(MirrorError::new(&format!(
+            "[get_referrers] {} returned {}",
+            url,
+            res.status()
+        )));
+    }
+    let body = res.text().await.unwrap_or_default();
+    let index = serde_json::from_str::<OCIIndex>(&body);
+    if index.is_err() {
+        return Err(MirrorError::new(&format!(
+            "[get_referrers] parsing {} {}",
+            url,
+            index.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    Ok(Some(index.unwrap()))
+}
+
+// tag of the referrers index for registries without the referrers api (sha256-<hex>)
+pub fn referrers_tag(digest: &str) -> String {
+    digest.replace(":", "-")
+}
+
+// list the referrers of a manifest, with the referrers api when the registry supports it
+// and from the referrers tag otherwise
+pub async fn find_referrers(
+    repo_url: &str,
+    token: &str,
+    subject: &str,
+) -> Result<Vec<Layer>, MirrorError> {
+    let url = format!("{}/referrers/{}", repo_url, subject);
+    if let Some(index) = get_referrers(&url, token).await? {
+        return Ok(index.manifests);
+    }
+    let url = format!("{}/manifests/{}", repo_url, referrers_tag(subject));
+    let manifest = find_manifest(&url, token).await?;
+    if manifest.is_none() {
+        return Ok(vec![]);
+    }
+    let index = serde_json::from_str::<OCIIndex>(&manifest.unwrap());
+    if index.is_err() {
+        return Err(MirrorError::new(&format!(
+            "[find_referrers] parsing {} {}",
+            url,
+            index.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    Ok(index.unwrap().manifests)
+}
+
+// push a manifest with a subject by digest, when the registry does not answer with
+// the OCI-Subject header the descriptor is added to the referrers tag of the subject
+pub async fn push_referrer(
+    repo_url: &str,
+    token: &str,
+    subject: &str,
+    descriptor: &Layer,
+    manifest: String,
+) -> Result<(), MirrorError> {
+    let url = format!("{}/manifests/{}", repo_url, descriptor.digest);
+    let processed = send_manifest(&url, token, &descriptor.media_type, manifest).await?;
+    if processed.as_deref() == Some(subject) {
+        return Ok(());
+    }
+    debug!(
+        "referrers api not supported, using tag {}",
+        referrers_tag(subject)
+    );
+    let url = format!("{}/manifests/{}", repo_url, referrers_tag(subject));
+    // an unreadable referrers index is replaced
+    let mut index = find_manifest(&url, token)
+        .await?
+        .and_then(|data| serde_json::from_str::<OCIIndex>(&data).ok())
+        .unwrap_or(OCIIndex {
+            schema_version: 2,
+            media_type: Some(OCI_INDEX.to_string()),
+            manifests: vec![],
+        });
+    if index
+        .manifests
+        .iter()
+        .any(|entry| entry.digest == descriptor.digest)
+    {
+        return Ok(());
+    }
+    index.manifests.push(descriptor.clone());
+    put_manifest(
+        &url,
+        token,
+        OCI_INDEX,
+        serde_json::to_string(&index).unwrap(),
+    )
+    .await
+}
+
+// blobs larger than a chunk are uploaded in chunks (resumable)
+const CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+const MAX_ATTEMPTS: u32 = 4;
+const INITIAL_BACKOFF: u64 = 1;
+
+// result of a blob push
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlobPush {
+    Exists,
+    Pushed,
+}
+
+// upload session kept across attempts so a failed upload resumes from the last chunk
+#[derive(Default)]
+struct UploadState {
+    location: Option<String>,
+    offset: u64,
+}
+
+fn to_error<E: ToString>(err: E) -> MirrorError {
+    MirrorError::new(&err.to_string().to_lowercase())
+}
+
+// a failed attempt is retried for network errors, 5xx and 429,
+// other errors (auth, bad request, local io) fail the push at once
+enum AttemptError {
+    Retry(MirrorError),
+    Fatal(MirrorError),
+}
+
+fn network_error(err: reqwest::Error) -> AttemptError {
+    AttemptError::Retry(to_error(err))
+}
+
+fn local_error(err: MirrorError) -> AttemptError {
+    AttemptError::Fatal(err)
+}
+
+fn status_error(context: &str, status: StatusCode) -> AttemptError {
+    let err = MirrorError::new(&format!("{} returned {}", context, status));
+    match status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+        true => AttemptError::Retry(err),
+        false => AttemptError::Fatal(err),
+    }
+}
+
+// upload locations can be relative to the registry host
+fn upload_url(repo_url: &str, location: &str) -> String {
+    match location.starts_with("/") {
+        true => {
+            let host_end = repo_url.find("/v2/").unwrap_or(repo_url.len());
+            format!("{}{}", &repo_url[..host_end], location)
+        }
+        false => location.to_string(),
+    }
+}
+
+fn location_header(res: &reqwest::Response, repo_url: &str) -> Result<String, MirrorError> {
+    match res.headers().get(LOCATION).and_then(|l| l.to_str().ok()) {
+        Some(location) => Ok(upload_url(repo_url, location)),
+        None => Err(MirrorError::new("upload response has no location header")),
+    }
+}
+
+// push a blob unless the registry already has it, failed attempts are retried
+// with exponential backoff and resume the upload where it stopped
+pub async fn push_blob(
+    repo_url: &str,
+    token: &str,
+    path: &str,
+    blob_digest: &str,
+) -> Result<BlobPush, MirrorError> {
+    let client = reqwest::Client::new();
+    let mut state = UploadState::default();
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        let res = push_blob_attempt(&client, repo_url, token, path, blob_digest, &mut state).await;
+        match res {
+            Ok(result) => return Ok(result),
+            Err(AttemptError::Fatal(err)) => {
+                return Err(MirrorError::new(&format!(
+                    "[push_blob] {} {}",
+                    blob_digest,
+                    err.to_string().to_lowercase()
+                )));
+            }
+            Err(AttemptError::Retry(err)) if attempt >= MAX_ATTEMPTS => {
+                return Err(MirrorError::new(&format!(
+                    "[push_blob] {} failed after {} attempts {}",
+                    blob_digest,
+                    attempt,
+                    err.to_string().to_lowercase()
+                )));
+            }
+            Err(AttemptError::Retry(err)) => {
+                warn!(
+                    "[push_blob] {} attempt {} failed, retrying in {}s {}",
+                    blob_digest,
+                    attempt,
+                    backoff,
+                    err.to_string().to_lowercase()
+                );
+                sleep(Duration::from_secs(backoff)).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+async fn push_blob_attempt(
+    client: &reqwest::Client,
+    repo_url: &str,
+    token: &str,
+    path: &str,
+    blob_digest: &str,
+    state: &mut UploadState,
+) -> Result<BlobPush, AttemptError> {
+    let size = fs::metadata(path)
+        .map_err(|e| {
+            local_error(MirrorError::new(&format!(
+                "reading {} {}",
+                path,
+                e.to_string().to_lowercase()
+            )))
+        })?
+        .len();
+    let location = match state.location.clone() {
+        // resume an upload, the registry reports the range it has received
+        Some(location) => {
+            let res = with_token(client.get(&location), token)
+                .send()
+                .await
+                .map_err(network_error)?;
+            if !res.status().is_success() {
+                // the upload session expired, start again
+                state.location = None;
+                state.offset = 0;
+                return Err(AttemptError::Retry(MirrorError::new(&format!(
+                    "upload status returned {}",
+                    res.status()
+                ))));
+            }
+            state.offset = res
+                .headers()
+                .get(RANGE)
+                .and_then(|r| r.to_str().ok())
+                .and_then(|r| r.split_once("-"))
+                .and_then(|(_, end)| end.parse::<u64>().ok())
+                .map_or(0, |end| end + 1);
+            location
+        }
+        None => {
+            let url = format!("{}/blobs/{}", repo_url, blob_digest);
+            let res = with_token(client.head(&url), token)
+                .send()
+                .await
+                .map_err(network_error)?;
+            if res.status().is_success() {
+                return Ok(BlobPush::Exists);
+            }
+            let url = format!("{}/blobs/uploads/", repo_url);
+            let res = with_token(client.post(&url), token)
+                .send()
+                .await
+                .map_err(network_error)?;
+            if res.status() != StatusCode::ACCEPTED {
+                return Err(status_error("starting upload", res.status()));
+            }
+            let location = location_header(&res, repo_url).map_err(local_error)?;
+            state.location = Some(location.clone());
+            state.offset = 0;
+            location
+        }
+    };
+
+    let mut file = File::open(path).map_err(|e| {
+        local_error(MirrorError::new(&format!(
+            "opening {} {}",
+            path,
+            e.to_string().to_lowercase()
+        )))
+    })?;
+    let mut location = location;
+    // small blobs are sent with the closing request (monolithic upload)
+    let mut body = vec![];
+    if size <= CHUNK_SIZE && state.offset == 0 {
+        file.read_to_end(&mut body)
+            .map_err(|e| local_error(to_error(e)))?;
+    } else {
+        while state.offset < size {
+            let len = CHUNK_SIZE.min(size - state.offset);
+            let mut chunk = vec![0; len as usize];
+            file.seek(SeekFrom::Start(state.offset))
+                .and_then(|_| file.read_exact(&mut chunk))
+                .map_err(|e| local_error(to_error(e)))?;
+            let res = with_token(client.patch(&location), token)
+                .header(CONTENT_TYPE, "application/octet-stream")
+                .header(
+                    CONTENT_RANGE,
+                    format!("{}-{}", state.offset, state.offset + len - 1),
+                )
+                .header(CONTENT_LENGTH, len)
+                .body(chunk)
+                .send()
+                .await
+                .map_err(network_error)?;
+            if res.status() != StatusCode::ACCEPTED {
+                let context = format!("uploading chunk at {}", state.offset);
+                return Err(status_error(&context, res.status()));
+            }
+            location = location_header(&res, repo_url).map_err(local_error)?;
+            state.location = Some(location.clone());
+            state.offset += len;
+        }
+    }
+    let separator = match location.contains("?") {
+        true => "&",
+        false => "?",
+    };
+    let url = format!("{}{}digest={}", location, separator, blob_digest);
+    let res = with_token(client.put(&url), token)
+        .header(CONTENT_TYPE, "application/octet-stream")
+        .header(CONTENT_LENGTH, body.len())
+        .body(body)
+        .send()
+        .await
+        .map_err(network_error)?;
+    if res.status() != StatusCode::CREATED {
+        return Err(status_error("completing upload", res.status()));
+    }
+    Ok(BlobPush::Pushed)
+}
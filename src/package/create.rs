@@ -1,37 +1,344 @@
-use crate::api::schema::{Layer, Manifest, ManifestPlatform, OCIIndex};
-use crate::{Annotations, SignatureJson};
-use base64::prelude::*;
-use flate2::write::GzEncoder;
-use flate2::Compression;
+use crate::api::schema::{
+    Annotations, BaseConfig, Config, Labels, Layer, LayerCompression, Manifest, ManifestPlatform,
+    OCIIndex, PackageFile, PackageOptions, Platform, Rootfs, Service,
+};
+use crate::config::read::service_platforms;
+use crate::oci::layout::write_layout_marker;
+use crate::oci::registry::{
+    OCI_INDEX, OCI_LAYER_GZIP, OCI_LAYER_TAR, OCI_LAYER_ZSTD, OCI_MANIFEST,
+};
+use crate::package::signature::{
+    read_signature, signature_dir, signature_path, SIGNATURE_ARTIFACT_TYPE,
+};
+use crate::SignatureJson;
+use chrono::{DateTime, SecondsFormat, Utc};
+use flate2::{Compression, GzBuilder};
+use glob::glob;
 use mirror_error::MirrorError;
 use mirror_utils::fs_handler;
 use sha2::{Digest, Sha256};
 use sha256::digest;
 use std::fs::File;
 use std::io;
-use std::io::Read;
-use std::os::unix::fs::MetadataExt;
-use std::{fs, usize};
+use std::io::{BufWriter, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+use tar::{EntryType, Header};
 
-// first pick up the binary file and create a tar.gz
-pub async fn create_signed_artifact(name: String, path: String) -> Result<(), MirrorError> {
-    let tar_gz_file = format!("generated/{}.tar.gz", name.clone());
-    let tar = File::create(tar_gz_file.clone()).unwrap();
-    let enc = GzEncoder::new(tar, Compression::default());
-    let mut tar_file = tar::Builder::new(enc);
-    let res_binary = File::open(format!("{}/{}", path, name.clone()));
+// packaging options, reproducible mode is also enabled when SOURCE_DATE_EPOCH is set
+pub fn package_options(
+    reproducible: bool,
+    compression: Option<LayerCompression>,
+) -> PackageOptions {
+    let epoch = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|value| value.trim().parse::<i64>().ok());
+    PackageOptions {
+        reproducible: reproducible || epoch.is_some(),
+        source_date_epoch: epoch.unwrap_or(0),
+        compression,
+    }
+}
+
+// a file packaged in a layer, source on disk and path in the image
+struct LayerEntry {
+    source: PathBuf,
+    destination: PathBuf,
+    mode: Option<u32>,
+}
+
+// the files matching the declared sources, at their destination in the image
+fn file_entries(files: &[PackageFile]) -> Result<Vec<LayerEntry>, MirrorError> {
+    let mut entries = vec![];
+    for file in files.iter() {
+        let mode = match file.mode.as_ref() {
+            Some(mode) => {
+                let res = u32::from_str_radix(mode.trim_start_matches("0o"), 8);
+                if res.is_err() {
+                    return Err(MirrorError::new(&format!(
+                        "file {} has an invalid mode {}",
+                        file.source, mode
+                    )));
+                }
+                Some(res.unwrap())
+            }
+            None => None,
+        };
+        let res = glob(&file.source);
+        if res.is_err() {
+            return Err(MirrorError::new(&format!(
+                "file pattern {} {}",
+                file.source,
+                res.err().unwrap().to_string().to_lowercase()
+            )));
+        }
+        let matches = res
+            .unwrap()
+            .filter_map(Result::ok)
+            .collect::<Vec<PathBuf>>();
+        if matches.is_empty() {
+            return Err(MirrorError::new(&format!("no files match {}", file.source)));
+        }
+        let destination = file.destination.clone().unwrap_or("/".to_string());
+        let is_dir = destination.ends_with('/') || matches.len() > 1;
+        let destination = Path::new(destination.trim_start_matches('/'));
+        for source in matches {
+            let destination = match is_dir {
+                true => destination.join(source.file_name().unwrap_or_default()),
+                false => destination.to_path_buf(),
+            };
+            entries.push(LayerEntry {
+                source,
+                destination,
+                mode,
+            });
+        }
+    }
+    Ok(entries)
+}
+
+// list the entries of a directory recursively (relative paths)
+fn collect_entries(root: &Path, dir: &Path, entries: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        entries.push(path.strip_prefix(root).unwrap().to_path_buf());
+        if fs::symlink_metadata(&path)?.is_dir() {
+            collect_entries(root, &path, entries)?;
+        }
+    }
+    Ok(())
+}
+
+// append a file, directory or symlink, in reproducible mode the header is normalized
+// (owner 0:0, no user or group names, fixed mtime and 0755/0644 permissions)
+fn append_single<W: Write>(
+    builder: &mut tar::Builder<W>,
+    src: &Path,
+    dest: &Path,
+    mode: Option<u32>,
+    options: &PackageOptions,
+) -> io::Result<()> {
+    let meta = fs::symlink_metadata(src)?;
+    let symlink = meta.file_type().is_symlink();
+    let mut header = Header::new_gnu();
+    header.set_metadata(&meta);
+    if options.reproducible {
+        let executable = meta.permissions().mode() & 0o111 != 0;
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_username("")?;
+        header.set_groupname("")?;
+        header.set_mtime(options.source_date_epoch as u64);
+        // symlinks first, their own mode is always executable
+        header.set_mode(match (symlink, meta.is_dir(), executable) {
+            (true, _, _) => 0o777,
+            (_, true, _) | (_, _, true) => 0o755,
+            _ => 0o644,
+        });
+    }
+    if meta.is_dir() {
+        header.set_entry_type(EntryType::Directory);
+        header.set_size(0);
+        builder.append_data(&mut header, dest, io::empty())
+    } else if symlink {
+        header.set_entry_type(EntryType::Symlink);
+        header.set_size(0);
+        builder.append_link(&mut header, dest, fs::read_link(src)?)
+    } else {
+        if let Some(mode) = mode {
+            header.set_mode(mode);
+        }
+        builder.append_data(&mut header, dest, File::open(src)?)
+    }
+}
+
+// append the layer entries sorted by destination, directories are added recursively
+fn append_entries<W: Write>(
+    builder: &mut tar::Builder<W>,
+    entries: Vec<LayerEntry>,
+    options: &PackageOptions,
+) -> io::Result<()> {
+    let mut files = vec![];
+    for entry in entries {
+        if fs::metadata(&entry.source)?.is_dir() {
+            let mut children = vec![];
+            collect_entries(&entry.source, &entry.source, &mut children)?;
+            for child in children {
+                files.push(LayerEntry {
+                    source: entry.source.join(&child),
+                    destination: entry.destination.join(&child),
+                    mode: entry.mode,
+                });
+            }
+        } else {
+            files.push(entry);
+        }
+    }
+    files.sort_by(|a, b| a.destination.cmp(&b.destination));
+    for file in files {
+        append_single(builder, &file.source, &file.destination, file.mode, options)?;
+    }
+    Ok(())
+}
+
+// package the binary of each platform as its own image manifest,
+// all manifests are referenced from the oci index (multi-architecture image)
+pub async fn create_signed_artifact(
+    working_dir: &str,
+    service: &Service,
+    options: &PackageOptions,
+) -> Result<(), MirrorError> {
+    let mut manifests = vec![];
+    for platform in service_platforms(service) {
+        manifests.push(create_platform_artifact(working_dir, service, &platform, options).await?);
+    }
+    let index = OCIIndex {
+        schema_version: 2,
+        media_type: Some(OCI_INDEX.to_string()),
+        manifests,
+    };
+    let index_json = serde_json::to_string(&index);
+    fs_handler(
+        format!("{}/generated/{}/index.json", working_dir, service.name),
+        "write",
+        Some(index_json.unwrap()),
+    )
+    .await?;
+    write_layout_marker(&format!("{}/generated/{}", working_dir, service.name)).await?;
+    Ok(())
+}
+
+// the binary layer (binary at the root of the image and the service files),
+// followed by a layer for each declared group
+async fn create_platform_artifact(
+    working_dir: &str,
+    service: &Service,
+    platform: &Platform,
+    options: &PackageOptions,
+) -> Result<Layer, MirrorError> {
+    let binary = Path::new(&platform.binary_path).join(&service.name);
+    let res_binary = File::open(&binary);
     if res_binary.is_err() {
         let err = MirrorError::new(&format!(
-            "reading binary microservice (maybe needs to be compiled ?) {}",
+            "reading binary microservice {} (maybe needs to be compiled ?) {}",
+            platform.architecture,
             res_binary.err().unwrap().to_string().to_lowercase()
         ));
         return Err(err);
     }
-    //let mut binary = res_binary.unwrap();
-    tar_file.append_dir_all(".", path.clone()).unwrap();
-    tar_file.into_inner().unwrap().finish().unwrap();
+    let mut entries = vec![LayerEntry {
+        source: binary,
+        destination: PathBuf::from(&service.name),
+        mode: None,
+    }];
+    entries.extend(file_entries(&service.files.clone().unwrap_or_default())?);
+    let mut layers =
+        vec![create_layer(working_dir, service, platform, "binary", entries, options).await?];
+    for group in service.layers.clone().unwrap_or_default().iter() {
+        let entries = file_entries(&group.files)?;
+        layers.push(
+            create_layer(
+                working_dir,
+                service,
+                platform,
+                &group.name,
+                entries,
+                options,
+            )
+            .await?,
+        );
+    }
+    let mnfst_platform = ManifestPlatform {
+        architecture: platform.architecture.clone(),
+        os: platform.os.clone().unwrap_or("linux".to_string()),
+    };
+    create_oci_manifest(working_dir, service, mnfst_platform, layers, options).await
+}
+
+// compress a tar file (gzip header without file name and a zero timestamp)
+fn compress_layer(src: &str, dest: &str, compression: LayerCompression) -> io::Result<()> {
+    let mut input = File::open(src)?;
+    let output = File::create(dest)?;
+    match compression {
+        LayerCompression::Gzip => {
+            let mut enc = GzBuilder::new()
+                .mtime(0)
+                .write(output, Compression::default());
+            io::copy(&mut input, &mut enc)?;
+            enc.finish()?;
+        }
+        LayerCompression::Zstd => {
+            zstd::stream::copy_encode(input, output, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+        }
+        LayerCompression::None => {
+            io::copy(&mut input, &mut BufWriter::new(output))?;
+        }
+    }
+    Ok(())
+}
 
-    let mut file = std::fs::File::open(tar_gz_file.clone()).unwrap();
+// create a layer blob (plain tar, tar+gzip or tar+zstd), returns the layer descriptor and the diff_id
+async fn create_layer(
+    working_dir: &str,
+    service: &Service,
+    platform: &Platform,
+    group: &str,
+    entries: Vec<LayerEntry>,
+    options: &PackageOptions,
+) -> Result<(Layer, String), MirrorError> {
+    let name = service.name.clone();
+    let tar_path = format!(
+        "{}/generated/{}-{}-{}.tar",
+        working_dir, name, platform.architecture, group
+    );
+    let tar = File::create(tar_path.clone()).unwrap();
+    let mut tar_file = tar::Builder::new(tar);
+    let res = append_entries(&mut tar_file, entries, options);
+    if res.is_err() {
+        return Err(MirrorError::new(&format!(
+            "creating layer {} {}",
+            group,
+            res.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    tar_file.into_inner().unwrap().flush().unwrap();
+
+    // the image config references the digest of the uncompressed layer (diff_id)
+    let mut file = std::fs::File::open(tar_path.clone()).unwrap();
+    let mut hasher = Sha256::new();
+    let res = io::copy(&mut file, &mut hasher);
+    if res.is_err() {
+        let err = MirrorError::new(&format!(
+            "creating diff_id sh256 hash {}",
+            res.err().unwrap().to_string().to_lowercase()
+        ));
+        return Err(err);
+    }
+    let diff_id = format!("sha256:{:x}", hasher.finalize());
+    // the command line option takes precedence over the service config
+    let compression = options
+        .compression
+        .or(service.compression)
+        .unwrap_or_default();
+    let (layer_file, media_type) = match compression {
+        LayerCompression::None => (tar_path.clone(), OCI_LAYER_TAR),
+        LayerCompression::Gzip => (format!("{}.gz", tar_path), OCI_LAYER_GZIP),
+        LayerCompression::Zstd => (format!("{}.zst", tar_path), OCI_LAYER_ZSTD),
+    };
+    if compression != LayerCompression::None {
+        let res = compress_layer(&tar_path, &layer_file, compression);
+        if res.is_err() {
+            return Err(MirrorError::new(&format!(
+                "compressing layer {} {}",
+                group,
+                res.err().unwrap().to_string().to_lowercase()
+            )));
+        }
+        let _ = fs::remove_file(&tar_path);
+    }
+
+    let mut file = std::fs::File::open(layer_file.clone()).unwrap();
     let mut hasher = Sha256::new();
     let res = io::copy(&mut file, &mut hasher);
     if res.is_err() {
@@ -42,10 +349,10 @@ pub async fn create_signed_artifact(name: String, path: String) -> Result<(), Mi
         return Err(err);
     }
     let digest = format!("{:x}", hasher.finalize());
-    let blobs_path = format!("generated/{}/blobs/sha256", name.clone());
+    let blobs_path = format!("{}/generated/{}/blobs/sha256", working_dir, name.clone());
     fs_handler(blobs_path.clone(), "create_dir", None).await?;
     let rename = fs::rename(
-        tar_gz_file,
+        layer_file,
         format!("{}/{}", blobs_path.clone(), digest.clone()),
     );
     if rename.is_err() {
@@ -56,124 +363,162 @@ pub async fn create_signed_artifact(name: String, path: String) -> Result<(), Mi
         return Err(err);
     }
     let metadata = fs::metadata(format!("{}/{}", blobs_path.clone(), digest.clone())).unwrap();
-    create_oci_manifest(name.clone(), digest, metadata.size() as usize).await?;
-    Ok(())
+    let layer = Layer {
+        media_type: media_type.to_string(),
+        digest: format!("sha256:{}", digest),
+        size: metadata.size() as i64,
+        annotations: Some(Annotations {
+            image_title: Some(group.to_string()),
+            image_created: None,
+            ref_name: None,
+        }),
+        platform: None,
+        artifact_type: None,
+    };
+    Ok((layer, diff_id))
+}
+
+// build the oci image config from the service definition
+pub fn create_image_config(
+    service: &Service,
+    platform: &ManifestPlatform,
+    diff_ids: Vec<String>,
+    options: &PackageOptions,
+) -> BaseConfig {
+    let created = match options.reproducible {
+        true => DateTime::from_timestamp(options.source_date_epoch, 0).unwrap_or_default(),
+        false => Utc::now(),
+    };
+    let created = created.to_rfc3339_opts(SecondsFormat::Secs, true);
+    let env = service
+        .env
+        .clone()
+        .unwrap_or_default()
+        .iter()
+        .map(|kv| format!("{}={}", kv.name, kv.value))
+        .collect();
+    // args are passed as name value pairs (same as start_service)
+    let cmd = service
+        .args
+        .clone()
+        .unwrap_or_default()
+        .iter()
+        .flat_map(|kv| vec![kv.name.clone(), kv.value.clone()])
+        .collect();
+    BaseConfig {
+        created: created.clone(),
+        architecture: platform.architecture.clone(),
+        os: platform.os.clone(),
+        config: Config {
+            env,
+            entrypoint: vec![format!("/{}", service.name)],
+            cmd,
+            working_dir: "/".to_string(),
+            labels: Labels {
+                title: service.name.clone(),
+                version: service.version.clone(),
+                description: service.description.clone(),
+                authors: service.authors.join(", "),
+                created,
+            },
+        },
+        rootfs: Rootfs {
+            rootfs_type: "layers".to_string(),
+            diff_ids,
+        },
+    }
 }
 
+// write the image config and manifest blobs, returns the index entry for the manifest
 pub async fn create_oci_manifest(
-    name: String,
-    ms_hash: String,
-    ms_size: usize,
-) -> Result<(), MirrorError> {
+    working_dir: &str,
+    service: &Service,
+    mnfst_platform: ManifestPlatform,
+    layers: Vec<(Layer, String)>,
+    options: &PackageOptions,
+) -> Result<Layer, MirrorError> {
+    let name = service.name.clone();
+    // layers are applied in order, the config lists the diff_id of each layer
+    let (vec_layers, diff_ids): (Vec<Layer>, Vec<String>) = layers.into_iter().unzip();
     // create the referenced image manifest
-    let cfg = fs_handler("templates/config-simple.json".to_string(), "read", None).await?;
+    let image_config = create_image_config(service, &mnfst_platform, diff_ids, options);
+    let cfg = serde_json::to_string(&image_config).unwrap();
     let hash = digest(cfg.as_bytes());
     let cfg_layer = Layer {
         media_type: "application/vnd.oci.image.config.v1+json".to_string(),
         digest: format!("sha256:{}", hash.clone()),
         size: cfg.len() as i64,
         annotations: None,
+        platform: None,
+        artifact_type: None,
     };
-    let blob_cfg = format!("generated/{}/blobs/sha256/{}", name, hash);
+    let blob_cfg = format!("{}/generated/{}/blobs/sha256/{}", working_dir, name, hash);
     fs_handler(blob_cfg, "write", Some(cfg.to_string())).await?;
 
-    let ms_layer = Layer {
-        media_type: "application/vnd.oci.image.layer.v1.tar+gzip".to_string(),
-        digest: format!("sha256:{}", ms_hash),
-        size: ms_size as i64,
-        annotations: None,
-    };
-    let vec_layers = vec![ms_layer];
-    let mnfst_platform = ManifestPlatform {
-        architecture: "amd64".to_string(),
-        os: "linux".to_string(),
-    };
-
     let manifest = Manifest {
         schema_version: Some(2),
         artifact_type: None,
-        media_type: Some("application/vnd.oci.image.manifest.v1+json".to_string()),
+        media_type: Some(OCI_MANIFEST.to_string()),
         config: Some(cfg_layer),
         layers: Some(vec_layers),
         digest: None,
-        platform: Some(mnfst_platform),
+        platform: Some(mnfst_platform.clone()),
         size: None,
         subject: None,
     };
 
     let manifest_json = serde_json::to_string(&manifest).unwrap();
     let hash_json = digest(&manifest_json);
-    let manifest_blob_json = format!("generated/{}/blobs/sha256/{}", name, hash_json);
+    let manifest_blob_json = format!(
+        "{}/generated/{}/blobs/sha256/{}",
+        working_dir, name, hash_json
+    );
     fs_handler(manifest_blob_json, "write", Some(manifest_json.clone())).await?;
 
-    // create oci index first
-    let index = Layer {
-        media_type: "application/vnd.oci.image.manifest.v1+json".to_string(),
+    Ok(Layer {
+        media_type: OCI_MANIFEST.to_string(),
         digest: format!("sha256:{}", hash_json),
         size: manifest_json.len() as i64,
         annotations: None,
-    };
-    let vec_manifests = vec![index];
-    let index = OCIIndex {
-        schema_version: 2,
-        manifests: vec_manifests.clone(),
-    };
-    let index_json = serde_json::to_string(&index);
-    fs_handler(
-        format!("generated/{}/index.json", name),
-        "write",
-        Some(index_json.unwrap()),
-    )
-    .await?;
-
-    Ok(())
+        platform: Some(mnfst_platform),
+        artifact_type: None,
+    })
 }
 
 // referral manifest (oci format, used to create signature layer)
 pub async fn create_referral_manifest(
+    working_dir: &str,
     name: String,
     referral_url_digest: String,
     referral_size: i64,
     format: String,
 ) -> Result<(), MirrorError> {
     if format == "dockerv2" {
-        fs_handler(format!("generated/{}/signature", name), "create_dir", None).await?;
+        fs_handler(
+            format!("{}/generated/{}/signature", working_dir, name),
+            "create_dir",
+            None,
+        )
+        .await?;
     } else {
         fs_handler(
-            format!("generated/{}/signature/blobs/sha256", name),
+            format!("{}/generated/{}/signature/blobs/sha256", working_dir, name),
             "create_dir",
             None,
         )
         .await?;
     }
-    let mut artifact_buf = vec![];
-    let res_file = File::open(format!(".ssh/{}-signature", name.clone()));
-    if res_file.is_err() {
-        let err = MirrorError::new(&format!(
-            "opening artifact file {}",
-            res_file.err().unwrap().to_string().to_lowercase()
-        ));
-        return Err(err);
-    }
-    let res_buf = res_file.unwrap().read_to_end(&mut artifact_buf);
-    if res_buf.is_err() {
-        let err = MirrorError::new(&format!(
-            "reading in buffer {}",
-            res_buf.err().unwrap().to_string().to_lowercase()
-        ));
-        return Err(err);
-    }
-    // base64 encode data signed file
-    let encoded = BASE64_STANDARD.encode(artifact_buf);
-    let sig_json = SignatureJson {
-        artifact: referral_url_digest.clone(),
-        signature: encoded.clone(),
-    };
-    let sig_json_contents = serde_json::to_string(&sig_json).unwrap();
+    let sig_json_contents = signature_json(
+        &signature_path(&signature_dir(&working_dir), &name),
+        referral_url_digest.clone(),
+    )?;
     let hash_sig_json = digest(&sig_json_contents.clone());
     if format == "dockerv2" {
         fs_handler(
-            format!("generated/{}/signature/{}", name, hash_sig_json),
+            format!(
+                "{}/generated/{}/signature/{}",
+                working_dir, name, hash_sig_json
+            ),
             "write",
             Some(sig_json_contents.clone()),
         )
@@ -181,8 +526,8 @@ pub async fn create_referral_manifest(
     } else {
         fs_handler(
             format!(
-                "generated/{}/signature/blobs/sha256/{}",
-                name, hash_sig_json
+                "{}/generated/{}/signature/blobs/sha256/{}",
+                working_dir, name, hash_sig_json
             ),
             "write",
             Some(sig_json_contents.clone()),
@@ -193,12 +538,15 @@ pub async fn create_referral_manifest(
     let sig_annotations = Annotations {
         image_title: Some(hash_sig_json.clone()),
         image_created: None,
+        ref_name: None,
     };
     let sig_layer = Layer {
         digest: format!("sha256:{}", hash_sig_json.clone()),
         media_type: "application/json".to_string(),
         size: sig_json_contents.clone().len() as i64,
         annotations: Some(sig_annotations),
+        platform: None,
+        artifact_type: None,
     };
     // create the referenced image manifest
     let empty = "  ".to_string();
@@ -208,17 +556,22 @@ pub async fn create_referral_manifest(
         digest: format!("sha256:{}", hash.clone()),
         size: 2,
         annotations: None,
+        platform: None,
+        artifact_type: None,
     };
     if format == "dockerv2" {
         fs_handler(
-            format!("generated/{}/signature/{}", name, hash),
+            format!("{}/generated/{}/signature/{}", working_dir, name, hash),
             "write",
             Some(empty),
         )
         .await?;
     } else {
         fs_handler(
-            format!("generated/{}/signature/blobs/sha256/{}", name, hash),
+            format!(
+                "{}/generated/{}/signature/blobs/sha256/{}",
+                working_dir, name, hash
+            ),
             "write",
             Some(empty),
         )
@@ -231,11 +584,13 @@ pub async fn create_referral_manifest(
         digest: referral_url_digest.split("@").nth(1).unwrap().to_string(),
         size: referral_size,
         annotations: None,
+        platform: None,
+        artifact_type: None,
     };
     let vec_layers = vec![sig_layer];
     let manifest = Manifest {
         schema_version: Some(2),
-        artifact_type: Some("application/vnd.example.signature.v1+json".to_string()),
+        artifact_type: Some(SIGNATURE_ARTIFACT_TYPE.to_string()),
         media_type: Some("application/vnd.oci.image.manifest.v1+json".to_string()),
         config: Some(cfg_layer.clone()),
         layers: Some(vec_layers.clone()),
@@ -246,11 +601,20 @@ pub async fn create_referral_manifest(
     };
     let manifest_json = serde_json::to_string(&manifest).unwrap();
     if format == "dockerv2" {
-        let manifest_file = format!("generated/{}/signature/manifest.json", name.clone());
+        let manifest_file = format!(
+            "{}/generated/{}/signature/manifest.json",
+            working_dir,
+            name.clone()
+        );
         fs_handler(manifest_file, "write", Some(manifest_json.clone())).await?;
     } else {
         let hash = digest(&manifest_json);
-        let manifest_file = format!("generated/{}/signature/blobs/sha256/{}", name.clone(), hash);
+        let manifest_file = format!(
+            "{}/generated/{}/signature/blobs/sha256/{}",
+            working_dir,
+            name.clone(),
+            hash
+        );
         fs_handler(manifest_file, "write", Some(manifest_json.clone())).await?;
         // finally create an index.json
         let layer = Layer {
@@ -258,15 +622,18 @@ pub async fn create_referral_manifest(
             digest: format!("sha256:{}", hash),
             size: manifest_json.len() as i64,
             annotations: None,
+            platform: None,
+            artifact_type: None,
         };
         let vec_manifests = vec![layer];
         let index = OCIIndex {
             schema_version: 2,
+            media_type: None,
             manifests: vec_manifests.clone(),
         };
         let index_json = serde_json::to_string(&index);
         fs_handler(
-            format!("generated/{}/signature/index.json", name),
+            format!("{}/generated/{}/signature/index.json", working_dir, name),
             "write",
             Some(index_json.unwrap()),
         )
@@ -274,3 +641,195 @@ pub async fn create_referral_manifest(
     }
     Ok(())
 }
+
+// signature blob of a referrer, the signed payload, the base64 encoded signature, its algorithm and the signed artifact
+fn signature_json(signature_file: &str, artifact: String) -> Result<String, MirrorError> {
+    let signature = read_signature(signature_file);
+    if signature.is_none() {
+        return Err(MirrorError::new(&format!(
+            "reading signature {}",
+            signature_file
+        )));
+    }
+    let signature = signature.unwrap();
+    let sig_json = SignatureJson {
+        artifact,
+        algorithm: signature.algorithm,
+        payload: signature.payload,
+        signature: signature.signature,
+    };
+    Ok(serde_json::to_string(&sig_json).unwrap())
+}
+
+// write the signature referrer of a manifest (empty config, signature blob and
+// a manifest with the signed manifest as subject) in an oci layout,
+// returns the descriptor of the referrer manifest
+pub async fn create_signature_referrer(
+    layout_dir: &str,
+    signature_file: &str,
+    subject: &Layer,
+) -> Result<Layer, MirrorError> {
+    let sig_json_contents = signature_json(signature_file, subject.digest.clone())?;
+    let hash_sig_json = digest(&sig_json_contents);
+    fs_handler(
+        format!("{}/blobs/sha256/{}", layout_dir, hash_sig_json),
+        "write",
+        Some(sig_json_contents.clone()),
+    )
+    .await?;
+    let sig_layer = Layer {
+        digest: format!("sha256:{}", hash_sig_json),
+        media_type: "application/json".to_string(),
+        size: sig_json_contents.len() as i64,
+        annotations: None,
+        platform: None,
+        artifact_type: None,
+    };
+    // the empty descriptor as config
+    let empty = "{}".to_string();
+    let hash = digest(&empty);
+    fs_handler(
+        format!("{}/blobs/sha256/{}", layout_dir, hash),
+        "write",
+        Some(empty.clone()),
+    )
+    .await?;
+    let cfg_layer = Layer {
+        media_type: "application/vnd.oci.image.empty.v1+json".to_string(),
+        digest: format!("sha256:{}", hash),
+        size: empty.len() as i64,
+        annotations: None,
+        platform: None,
+        artifact_type: None,
+    };
+    let subject_ref = Layer {
+        media_type: subject.media_type.clone(),
+        digest: subject.digest.clone(),
+        size: subject.size,
+        annotations: None,
+        platform: None,
+        artifact_type: None,
+    };
+    let manifest = Manifest {
+        schema_version: Some(2),
+        artifact_type: Some(SIGNATURE_ARTIFACT_TYPE.to_string()),
+        media_type: Some(OCI_MANIFEST.to_string()),
+        config: Some(cfg_layer),
+        layers: Some(vec![sig_layer]),
+        digest: None,
+        platform: None,
+        size: None,
+        subject: Some(subject_ref),
+    };
+    let manifest_json = serde_json::to_string(&manifest).unwrap();
+    let hash = digest(&manifest_json);
+    fs_handler(
+        format!("{}/blobs/sha256/{}", layout_dir, hash),
+        "write",
+        Some(manifest_json.clone()),
+    )
+    .await?;
+    Ok(Layer {
+        media_type: OCI_MANIFEST.to_string(),
+        digest: format!("sha256:{}", hash),
+        size: manifest_json.len() as i64,
+        annotations: None,
+        platform: None,
+        artifact_type: Some(SIGNATURE_ARTIFACT_TYPE.to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use std::time::{Duration, SystemTime};
+
+    // a tree with an executable, a data file, a nested directory and a symlink
+    fn create_tree(root: &Path, modified: SystemTime, data_mode: u32) {
+        fs::create_dir_all(root.join("bin")).unwrap();
+        fs::create_dir_all(root.join("data/nested")).unwrap();
+        fs::write(root.join("bin/convey"), "binary").unwrap();
+        fs::set_permissions(root.join("bin/convey"), fs::Permissions::from_mode(0o750)).unwrap();
+        fs::write(root.join("data/nested/config.yaml"), "config").unwrap();
+        fs::set_permissions(
+            root.join("data/nested/config.yaml"),
+            fs::Permissions::from_mode(data_mode),
+        )
+        .unwrap();
+        let _ = fs::remove_file(root.join("data/current"));
+        symlink("nested/config.yaml", root.join("data/current")).unwrap();
+        for file in ["bin/convey", "data/nested/config.yaml"] {
+            File::options()
+                .write(true)
+                .open(root.join(file))
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn reproducible_layers() {
+        let dir = env::temp_dir().join(format!("mpm-create-{}", std::process::id()));
+        let working_dir = dir.join("working-dir").to_string_lossy().to_string();
+        fs::create_dir_all(format!("{}/generated", working_dir)).unwrap();
+        let tree = dir.join("tree");
+        env::set_var("SOURCE_DATE_EPOCH", "1700000000");
+        let service = Service {
+            name: "convey".to_string(),
+            binary_path: tree.join("bin").to_string_lossy().to_string(),
+            registry: "quay.io/acme/convey".to_string(),
+            version: "0.1.0".to_string(),
+            authors: vec![],
+            description: "".to_string(),
+            env: None,
+            args: None,
+            platforms: None,
+            files: None,
+            layers: None,
+            compression: None,
+        };
+        let platform = Platform {
+            architecture: "amd64".to_string(),
+            os: None,
+            binary_path: service.binary_path.clone(),
+        };
+        for compression in [LayerCompression::Gzip, LayerCompression::Zstd] {
+            let options = package_options(false, Some(compression));
+            assert!(options.reproducible);
+            let mut digests = vec![];
+            // the same tree with other timestamps and permissions
+            for (offset, data_mode) in [(0, 0o644), (3600, 0o600)] {
+                let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000 + offset);
+                create_tree(&tree, modified, data_mode);
+                let entries = vec![
+                    LayerEntry {
+                        source: tree.join("bin/convey"),
+                        destination: PathBuf::from("convey"),
+                        mode: None,
+                    },
+                    LayerEntry {
+                        source: tree.join("data"),
+                        destination: PathBuf::from("etc/convey"),
+                        mode: None,
+                    },
+                ];
+                let (layer, diff_id) = create_layer(
+                    &working_dir,
+                    &service,
+                    &platform,
+                    "binary",
+                    entries,
+                    &options,
+                )
+                .await
+                .unwrap();
+                digests.push((layer.digest, diff_id));
+            }
+            assert_eq!(digests[0], digests[1], "{:?} layer", compression);
+        }
+        env::remove_var("SOURCE_DATE_EPOCH");
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
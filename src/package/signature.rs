@@ -1,46 +1,230 @@
+use crate::api::schema::{KeyAlgorithm, SignatureFile, SignaturePayload};
+use base64::prelude::*;
+use chrono::{SecondsFormat, Utc};
 use mirror_error::MirrorError;
 use mirror_utils::fs_handler;
+use openssl::ec::{EcGroup, EcKey};
 use openssl::hash::MessageDigest;
-use openssl::pkey::PKey;
+use openssl::nid::Nid;
+use openssl::pkey::{HasParams, HasPublic, Id, PKey, Private, Public};
 use openssl::rsa::Rsa;
 use openssl::sign::{Signer, Verifier};
-use std::fs::File;
+use openssl::symm::Cipher;
+use sha256::digest;
+use std::fs::{File, OpenOptions};
 use std::io::Read;
 use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::{env, fs};
 
-// Generate a keypair
-pub async fn create_keypair() -> Result<(), MirrorError> {
-    fs_handler(".ssh".to_string(), "create_dir", None).await?;
-    let private = Rsa::generate(2048).unwrap();
-    let public = PKey::from_rsa(private.clone()).unwrap();
-    let mut f_prv = File::create(".ssh/private.pem").expect("should create file");
-    let res_prv = f_prv.write_all(&private.private_key_to_pem().unwrap());
-    let metadata = f_prv.metadata().unwrap();
-    let mut permissions = metadata.permissions();
-    permissions.set_readonly(true);
-    if res_prv.is_err() {
-        let err = MirrorError::new(&format!(
-            "writing private key {}",
-            res_prv.err().unwrap().to_string().to_lowercase()
-        ));
-        return Err(err);
+// artifact type of the signature referrer manifests
+pub const SIGNATURE_ARTIFACT_TYPE: &str = "application/vnd.example.signature.v1+json";
+
+// passphrase of encrypted private keys (read from the environment, never from the command line)
+pub const KEY_PASSPHRASE_ENV: &str = "MICROSERVICE_KEY_PASSPHRASE";
+
+// detached signatures are kept in the working dir (package, sign and verify)
+pub fn signature_dir(working_dir: &str) -> String {
+    format!("{}/signatures", working_dir)
+}
+
+// path of the detached signature for an artifact
+pub fn signature_path(signature_dir: &str, name: &str) -> String {
+    format!("{}/{}-signature", signature_dir, name)
+}
+
+// Generate a keypair (private.pem and public.pem in the key dir)
+pub async fn create_keypair(key_dir: &str, algorithm: KeyAlgorithm) -> Result<(), MirrorError> {
+    fs_handler(key_dir.to_string(), "create_dir", None).await?;
+    let res_key = match algorithm {
+        KeyAlgorithm::Rsa => Rsa::generate(2048).and_then(PKey::from_rsa),
+        KeyAlgorithm::Ed25519 => PKey::generate_ed25519(),
+        KeyAlgorithm::EcdsaP256 => EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)
+            .and_then(|group| EcKey::generate(&group))
+            .and_then(PKey::from_ec_key),
+    };
+    if res_key.is_err() {
+        return Err(MirrorError::new(&format!(
+            "generating {:?} key {}",
+            algorithm,
+            res_key.err().unwrap().to_string().to_lowercase()
+        )));
     }
-    let mut f_pub = File::create(".ssh/public.pem").expect("should create file");
-    let metadata = f_pub.metadata().unwrap();
-    let mut permissions = metadata.permissions();
-    permissions.set_readonly(true);
-    let res_pub = f_pub.write_all(&public.public_key_to_pem().unwrap());
-    if res_pub.is_err() {
-        let err = MirrorError::new(&format!(
-            "writing blob {}",
-            res_pub.err().unwrap().to_string().to_lowercase()
-        ));
-        return Err(err);
+    let private = res_key.unwrap();
+    // the private key is encrypted (pkcs#8, aes-256-cbc) when a passphrase is set
+    let pem = match env::var(KEY_PASSPHRASE_ENV) {
+        Ok(passphrase) if !passphrase.is_empty() => private
+            .private_key_to_pem_pkcs8_passphrase(Cipher::aes_256_cbc(), passphrase.as_bytes()),
+        _ => private.private_key_to_pem_pkcs8(),
+    };
+    if pem.is_err() {
+        return Err(MirrorError::new(&format!(
+            "encoding private key {}",
+            pem.err().unwrap().to_string().to_lowercase()
+        )));
     }
+    write_key(&format!("{}/private.pem", key_dir), &pem.unwrap(), 0o600)?;
+    write_key(
+        &format!("{}/public.pem", key_dir),
+        &private.public_key_to_pem().unwrap(),
+        0o644,
+    )?;
     Ok(())
 }
 
-pub async fn sign_artifact(name: String, file: String) -> Result<(), MirrorError> {
+// write a key file, the mode is also applied when the file already exists
+fn write_key(path: &str, data: &[u8], mode: u32) -> Result<(), MirrorError> {
+    let res = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(path)
+        .and_then(|mut file| {
+            file.set_permissions(fs::Permissions::from_mode(mode))?;
+            file.write_all(data)
+        });
+    if res.is_err() {
+        return Err(MirrorError::new(&format!(
+            "writing key {} {}",
+            path,
+            res.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    Ok(())
+}
+
+// location of the private key, the key dir is used when none is set on the command
+pub fn private_key_source(key_dir: &str, private_key: &Option<String>) -> String {
+    private_key
+        .clone()
+        .unwrap_or(format!("{}/private.pem", key_dir))
+}
+
+// location of the public key, the key dir is used when none is set on the command
+pub fn public_key_source(key_dir: &str, public_key: &Option<String>) -> String {
+    public_key
+        .clone()
+        .unwrap_or(format!("{}/public.pem", key_dir))
+}
+
+// read a pem key from a path, env:<VAR> (the pem in an environment variable)
+// or fd:<N> (the pem read from an inherited file descriptor, for ci runners)
+fn read_key(source: &str) -> Result<Vec<u8>, MirrorError> {
+    if let Some(name) = source.strip_prefix("env:") {
+        let res = env::var(name);
+        if res.is_err() {
+            return Err(MirrorError::new(&format!(
+                "reading key from env {} {}",
+                name,
+                res.err().unwrap().to_string().to_lowercase()
+            )));
+        }
+        return Ok(res.unwrap().into_bytes());
+    }
+    let path = match source.strip_prefix("fd:") {
+        Some(fd) => format!("/dev/fd/{}", fd),
+        None => source.to_string(),
+    };
+    let res = fs::read(&path);
+    if res.is_err() {
+        return Err(MirrorError::new(&format!(
+            "reading key {} {}",
+            source,
+            res.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    Ok(res.unwrap())
+}
+
+// load a private key, encrypted keys are decrypted with the passphrase from the environment
+pub fn load_private_key(source: &str) -> Result<PKey<Private>, MirrorError> {
+    let buf = read_key(source)?;
+    let private_key = match env::var(KEY_PASSPHRASE_ENV) {
+        Ok(passphrase) => PKey::private_key_from_pem_passphrase(&buf, passphrase.as_bytes()),
+        Err(_) => PKey::private_key_from_pem(&buf),
+    };
+    if private_key.is_err() {
+        let hint = match String::from_utf8_lossy(&buf).contains("ENCRYPTED") {
+            true => format!(" (encrypted key, set {})", KEY_PASSPHRASE_ENV),
+            false => String::new(),
+        };
+        return Err(MirrorError::new(&format!(
+            "parsing private key {}{}",
+            private_key.err().unwrap().to_string().to_lowercase(),
+            hint
+        )));
+    }
+    Ok(private_key.unwrap())
+}
+
+// load a public key (same locations as the private key)
+pub fn load_public_key(source: &str) -> Result<PKey<Public>, MirrorError> {
+    let buf = read_key(source)?;
+    let public_key = PKey::public_key_from_pem(&buf);
+    if public_key.is_err() {
+        return Err(MirrorError::new(&format!(
+            "parsing public key {}",
+            public_key.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    Ok(public_key.unwrap())
+}
+
+// algorithm of a key, p-256 is the only supported curve
+fn key_algorithm<T: HasParams>(key: &PKey<T>) -> Result<KeyAlgorithm, MirrorError> {
+    match key.id() {
+        Id::RSA => Ok(KeyAlgorithm::Rsa),
+        Id::ED25519 => Ok(KeyAlgorithm::Ed25519),
+        Id::EC => {
+            let curve = key.ec_key().ok().and_then(|ec| ec.group().curve_name());
+            match curve {
+                Some(Nid::X9_62_PRIME256V1) => Ok(KeyAlgorithm::EcdsaP256),
+                _ => Err(MirrorError::new("unsupported ec curve (only p-256)")),
+            }
+        }
+        id => Err(MirrorError::new(&format!("unsupported key type {:?}", id))),
+    }
+}
+
+// key id, the sha256 of the public key (der)
+pub fn key_id<T: HasPublic>(key: &PKey<T>) -> Result<String, MirrorError> {
+    let der = key.public_key_to_der();
+    if der.is_err() {
+        return Err(MirrorError::new(&format!(
+            "encoding public key {}",
+            der.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    Ok(format!("sha256:{}", digest(der.unwrap().as_slice())))
+}
+
+// read a detached signature, files written before the algorithm was recorded hold the raw rsa signature
+pub fn read_signature(path: &str) -> Option<SignatureFile> {
+    let data = fs::read(path).ok()?;
+    match serde_json::from_slice::<SignatureFile>(&data) {
+        Ok(signature) => Some(signature),
+        Err(_) => Some(SignatureFile {
+            algorithm: KeyAlgorithm::Rsa,
+            payload: None,
+            signature: BASE64_STANDARD.encode(data),
+        }),
+    }
+}
+
+// sign an artifact with the private key (loaded once with load_private_key), the signature
+// covers a payload binding the name, version, manifest digest and binary digest,
+// the signature is written to signature_file (the name in the payload is the service name,
+// multi-architecture services have a signature file per platform)
+pub async fn sign_artifact(
+    private_key: &PKey<Private>,
+    signature_file: &str,
+    name: String,
+    file: String,
+    version: Option<String>,
+    manifest_digest: Option<String>,
+) -> Result<(), MirrorError> {
     let mut artifact_buf = vec![];
     let res_file = File::open(file.clone());
     if res_file.is_err() {
@@ -58,28 +242,32 @@ pub async fn sign_artifact(name: String, file: String) -> Result<(), MirrorError
         ));
         return Err(err);
     }
-    let res_prv = File::open(".ssh/private.pem");
-    if res_prv.is_err() {
-        let err = MirrorError::new(&format!(
-            "opening private key {}",
-            res_r.err().unwrap().to_string().to_lowercase()
-        ));
-        return Err(err);
-    }
-    let mut buf = vec![];
-    let res_r = res_prv.unwrap().read_to_end(&mut buf);
-    if res_r.is_err() {
-        let err = MirrorError::new(&format!(
-            "reading private key {}",
-            res_r.err().unwrap().to_string().to_lowercase()
-        ));
-        return Err(err);
-    }
-    let public_key = PKey::private_key_from_pem(&buf);
+    let algorithm = key_algorithm(private_key)?;
+    let payload = SignaturePayload {
+        name: name.clone(),
+        version,
+        manifest_digest,
+        binary_digest: format!("sha256:{}", digest(artifact_buf.as_slice())),
+        created: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        key_id: key_id(private_key)?,
+    };
+    let payload = serde_json::to_string(&payload).unwrap();
 
-    // Sign the data
-    let mut signer = Signer::new(MessageDigest::sha256(), &public_key.unwrap()).unwrap();
-    signer.update(&artifact_buf).unwrap();
+    // Sign the payload (ed25519 signs the message itself, rsa and ecdsa a sha256 digest)
+    let res_signature = match algorithm {
+        KeyAlgorithm::Ed25519 => Signer::new_without_digest(private_key)
+            .and_then(|mut signer| signer.sign_oneshot_to_vec(payload.as_bytes())),
+        _ => Signer::new(MessageDigest::sha256(), private_key).and_then(|mut signer| {
+            signer.update(payload.as_bytes())?;
+            signer.sign_to_vec()
+        }),
+    };
+    if res_signature.is_err() {
+        return Err(MirrorError::new(&format!(
+            "signing artifact {}",
+            res_signature.err().unwrap().to_string().to_lowercase()
+        )));
+    }
     let mut f_sign = File::create(file.clone() + &".signed").expect("should create signed file");
     let res_sign = f_sign.write_all(&artifact_buf);
     if res_sign.is_err() {
@@ -89,8 +277,20 @@ pub async fn sign_artifact(name: String, file: String) -> Result<(), MirrorError
         ));
         return Err(err);
     }
-    let signature = signer.sign_to_vec().unwrap();
-    let res_signature = File::create(format!(".ssh/{}-signature", name));
+    let signature = SignatureFile {
+        algorithm,
+        payload: Some(payload),
+        signature: BASE64_STANDARD.encode(res_signature.unwrap()),
+    };
+    if let Some(signature_dir) = Path::new(signature_file).parent() {
+        fs_handler(
+            signature_dir.to_string_lossy().to_string(),
+            "create_dir",
+            None,
+        )
+        .await?;
+    }
+    let res_signature = File::create(signature_file);
     if res_signature.is_err() {
         let err = MirrorError::new(&format!(
             "creating signature {}",
@@ -98,7 +298,9 @@ pub async fn sign_artifact(name: String, file: String) -> Result<(), MirrorError
         ));
         return Err(err);
     }
-    let res_sig = res_signature.unwrap().write_all(&signature);
+    let res_sig = res_signature
+        .unwrap()
+        .write_all(serde_json::to_string(&signature).unwrap().as_bytes());
     if res_sig.is_err() {
         let err = MirrorError::new(&format!(
             "writing signature artifact {}",
@@ -109,11 +311,23 @@ pub async fn sign_artifact(name: String, file: String) -> Result<(), MirrorError
     Ok(())
 }
 
-pub async fn verify_artifact(name: String, file: String) -> Result<bool, MirrorError> {
-    // Verify the data
+// verify a file against a signature with the public key,
+// the verifier is selected from the algorithm recorded with the signature
+pub fn verify_signature(
+    public_key: &PKey<Public>,
+    file: &str,
+    signature: &SignatureFile,
+) -> Result<bool, MirrorError> {
     let mut tar_buf = vec![];
-    let mut tgz_file = File::open(file.clone()).unwrap();
-    let res_r = tgz_file.read_to_end(&mut tar_buf);
+    let res_file = File::open(file);
+    if res_file.is_err() {
+        return Err(MirrorError::new(&format!(
+            "opening artifact {} {}",
+            file,
+            res_file.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    let res_r = res_file.unwrap().read_to_end(&mut tar_buf);
     if res_r.is_err() {
         let err = MirrorError::new(&format!(
             "verify artifact {}",
@@ -122,26 +336,154 @@ pub async fn verify_artifact(name: String, file: String) -> Result<bool, MirrorE
         return Err(err);
     }
 
-    let mut f_prv = File::open(".ssh/public.pem").unwrap();
-    let mut buf = vec![];
-    f_prv.read_to_end(&mut buf).expect("should read public key");
-    let public_key = PKey::public_key_from_pem(&buf);
-    let res_sig = File::open(format!(".ssh/{}-signature", name));
-    if res_sig.is_err() {
+    let key_type = key_algorithm(public_key)?;
+    if key_type != signature.algorithm {
+        return Err(MirrorError::new(&format!(
+            "signature algorithm {:?} does not match the {:?} public key",
+            signature.algorithm, key_type
+        )));
+    }
+    let decoded = BASE64_STANDARD.decode(&signature.signature);
+    if decoded.is_err() {
+        return Err(MirrorError::new(&format!(
+            "decoding signature {}",
+            decoded.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    let decoded = decoded.unwrap();
+    // signatures with a payload cover the payload, older signatures the raw file
+    let message = match signature.payload.as_ref() {
+        Some(payload) => payload.as_bytes(),
+        None => tar_buf.as_slice(),
+    };
+    let res = match signature.algorithm {
+        KeyAlgorithm::Ed25519 => Verifier::new_without_digest(public_key)
+            .and_then(|mut verifier| verifier.verify_oneshot(&decoded, message)),
+        _ => Verifier::new(MessageDigest::sha256(), public_key).and_then(|mut verifier| {
+            verifier.update(message)?;
+            verifier.verify(&decoded)
+        }),
+    };
+    // a malformed signature is a failed verification
+    if !res.unwrap_or(false) {
         return Ok(false);
     }
-    let mut signature_buf = vec![];
-    let res = res_sig.unwrap().read_to_end(&mut signature_buf);
-    if res.is_err() {
-        let err = MirrorError::new(&format!(
-            "reading to buffer {}",
-            res.err().unwrap().to_string().to_lowercase()
+    if signature.payload.is_none() {
+        return Ok(true);
+    }
+    // the payload is authentic, it must describe this file and this key
+    let payload = signature_payload(signature)?.unwrap();
+    let binary_digest = format!("sha256:{}", digest(tar_buf.as_slice()));
+    if payload.binary_digest != binary_digest {
+        return Err(MirrorError::new(&format!(
+            "signed binary digest {} does not match {}",
+            payload.binary_digest, binary_digest
+        )));
+    }
+    if payload.key_id != key_id(public_key)? {
+        return Err(MirrorError::new(&format!(
+            "signed key id {} does not match the public key",
+            payload.key_id
+        )));
+    }
+    Ok(true)
+}
+
+// claims of a signature, none for signatures created before the payload was signed
+pub fn signature_payload(
+    signature: &SignatureFile,
+) -> Result<Option<SignaturePayload>, MirrorError> {
+    if signature.payload.is_none() {
+        return Ok(None);
+    }
+    let payload = serde_json::from_str::<SignaturePayload>(signature.payload.as_ref().unwrap());
+    if payload.is_err() {
+        return Err(MirrorError::new(&format!(
+            "parsing signature payload {}",
+            payload.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    Ok(Some(payload.unwrap()))
+}
+
+// check the claims of a verified signature against the staged service, a signature
+// for another service, version or manifest is refused (no replay under another name or tag)
+pub fn verify_claims(
+    signature: &SignatureFile,
+    name: &str,
+    version: &str,
+    manifest_digest: &str,
+) -> Result<(), MirrorError> {
+    let payload = signature_payload(signature)?;
+    if payload.is_none() {
+        return Err(MirrorError::new(
+            "signature has no signed payload (package again to sign name, version and digest)",
         ));
-        return Err(err);
     }
-    let mut verifier =
-        Verifier::new(MessageDigest::sha256(), &public_key.as_ref().unwrap()).unwrap();
-    verifier.update(&tar_buf).unwrap();
-    let res = verifier.verify(&signature_buf).unwrap();
-    Ok(res)
+    let payload = payload.unwrap();
+    if payload.name != name {
+        return Err(MirrorError::new(&format!(
+            "signature is for service {} not {}",
+            payload.name, name
+        )));
+    }
+    if payload.version.as_deref() != Some(version) {
+        return Err(MirrorError::new(&format!(
+            "signature is for version {} not {}",
+            payload.version.unwrap_or_default(),
+            version
+        )));
+    }
+    if payload.manifest_digest.as_deref() != Some(manifest_digest) {
+        return Err(MirrorError::new(&format!(
+            "signature is for manifest {} not {}",
+            payload.manifest_digest.unwrap_or_default(),
+            manifest_digest
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn platform_signatures_name_the_service() {
+        let dir = env::temp_dir().join(format!("mpm-signature-{}", std::process::id()));
+        let signature_dir = dir.join("signatures").to_string_lossy().to_string();
+        fs::create_dir_all(&signature_dir).unwrap();
+        let private_key = PKey::generate_ed25519().unwrap();
+        let public_key =
+            PKey::public_key_from_der(&private_key.public_key_to_der().unwrap()).unwrap();
+        let platforms = [
+            ("amd64", format!("sha256:{}", "a".repeat(64))),
+            ("arm64", format!("sha256:{}", "b".repeat(64))),
+        ];
+        for (architecture, manifest_digest) in platforms.iter() {
+            let binary = dir.join(architecture).to_string_lossy().to_string();
+            fs::write(&binary, architecture.as_bytes()).unwrap();
+            let signature_file =
+                signature_path(&signature_dir, &format!("convey-{}", architecture));
+            sign_artifact(
+                &private_key,
+                &signature_file,
+                "convey".to_string(),
+                binary.clone(),
+                Some("0.1.0".to_string()),
+                Some(manifest_digest.clone()),
+            )
+            .await
+            .unwrap();
+            let signature = read_signature(&signature_file).unwrap();
+            assert!(verify_signature(&public_key, &binary, &signature).unwrap());
+            verify_claims(&signature, "convey", "0.1.0", manifest_digest).unwrap();
+            let other = format!("convey-{}", architecture);
+            assert!(verify_claims(&signature, &other, "0.1.0", manifest_digest).is_err());
+        }
+        // a platform signature does not cover the manifest of the other platform
+        let signature = read_signature(&signature_path(&signature_dir, "convey-amd64")).unwrap();
+        assert!(verify_claims(&signature, "convey", "0.1.0", &platforms[1].1).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
 }
@@ -0,0 +1,334 @@
+use crate::api::schema::{SignatureFile, TrustPolicy, TrustRule, TrustedKey};
+use crate::oci::reference::parse_reference;
+use crate::package::signature::{load_public_key, public_key_source, verify_signature};
+use glob::Pattern;
+use mirror_error::MirrorError;
+use mirror_utils::fs_handler;
+use openssl::pkey::{PKey, Public};
+use std::collections::HashMap;
+use std::path::Path;
+
+const TRUST_POLICY_FILE: &str = "trust-policy.yaml";
+
+// outcome of the trust policy for a service
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verification {
+    // the signature matches a trusted key (key id)
+    Trusted(String),
+    // no signature and the policy does not require one
+    Unsigned,
+    // no signature but the policy requires one
+    Missing,
+    // the signature matches none of the trusted keys (reason)
+    Untrusted(String),
+}
+
+// location of the trust policy, trust-policy.yaml in the key dir when not set
+pub fn trust_policy_path(key_dir: &str, trust_policy: &Option<String>) -> String {
+    trust_policy
+        .clone()
+        .unwrap_or(format!("{}/{}", key_dir, TRUST_POLICY_FILE))
+}
+
+// trust policy with its public keys, each key source is read once when the
+// policy is loaded (fd:<N> can only be read once), load errors are reported on use
+pub struct TrustStore {
+    pub policy: TrustPolicy,
+    keys: HashMap<String, Result<PKey<Public>, String>>,
+}
+
+impl TrustStore {
+    pub fn new(policy: TrustPolicy) -> Self {
+        let mut keys = HashMap::new();
+        let sources = policy
+            .rules
+            .iter()
+            .flat_map(|rule| policy.rule_keys(rule))
+            .map(|(_, source)| source);
+        for source in sources {
+            if !keys.contains_key(&source) {
+                let key = load_public_key(&source).map_err(|e| e.to_string().to_lowercase());
+                keys.insert(source, key);
+            }
+        }
+        TrustStore { policy, keys }
+    }
+}
+
+// read the trust policy, without a policy file the public key in the key dir
+// is trusted for every service and signatures are required
+pub async fn load_trust_policy(path: &str, key_dir: &str) -> Result<TrustStore, MirrorError> {
+    if !Path::new(path).exists() {
+        return Ok(TrustStore::new(single_key_policy(&public_key_source(
+            key_dir, &None,
+        ))));
+    }
+    let data = fs_handler(path.to_string(), "read", None).await?;
+    let res = serde_yaml::from_str::<TrustPolicy>(&data);
+    if res.is_err() {
+        return Err(MirrorError::new(&format!(
+            "[load_trust_policy] {} {}",
+            path,
+            res.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    let mut policy = res.unwrap();
+    // key paths are relative to the policy file
+    let base_dir = Path::new(path).parent().unwrap_or(Path::new("."));
+    for key in policy.keys.iter_mut() {
+        key.path = resolve_key(base_dir, &key.path);
+    }
+    for rule in policy.rules.iter() {
+        // rules refer to keys by id, a typo must not leave a rule without its key
+        let unknown = rule
+            .keys
+            .iter()
+            .find(|key| !policy.keys.iter().any(|trusted| &trusted.id == *key));
+        if let Some(key) = unknown {
+            return Err(MirrorError::new(&format!(
+                "[load_trust_policy] rule refers to unknown key id {}",
+                key
+            )));
+        }
+        if let Some(service) = rule.service.as_ref() {
+            if Pattern::new(service).is_err() {
+                return Err(MirrorError::new(&format!(
+                    "[load_trust_policy] invalid service pattern {}",
+                    service
+                )));
+            }
+        }
+    }
+    Ok(TrustStore::new(policy))
+}
+
+// policy trusting a single public key for every service, signatures are required
+// (the key location is its own id)
+pub fn single_key_policy(public_key: &str) -> TrustPolicy {
+    TrustPolicy {
+        keys: vec![TrustedKey {
+            id: public_key.to_string(),
+            path: public_key.to_string(),
+        }],
+        rules: vec![TrustRule {
+            service: None,
+            registry: None,
+            keys: vec![public_key.to_string()],
+            require_signature: true,
+        }],
+    }
+}
+
+fn resolve_key(base_dir: &Path, source: &str) -> String {
+    if source.starts_with("env:") || source.starts_with("fd:") || Path::new(source).is_absolute() {
+        return source.to_string();
+    }
+    base_dir.join(source).to_string_lossy().to_string()
+}
+
+// registry patterns match the registry, a namespace or the repository (at a path boundary)
+fn registry_matches(pattern: &str, registry: &str) -> bool {
+    let target = match parse_reference(registry) {
+        Ok(reference) => format!("{}/{}", reference.registry, reference.repository),
+        Err(_) => registry.to_string(),
+    };
+    let pattern = pattern.trim_end_matches("/");
+    target == pattern || target.starts_with(&format!("{}/", pattern))
+}
+
+impl TrustPolicy {
+    // first rule matching the service name and registry
+    pub fn rule(&self, name: &str, registry: &str) -> Option<&TrustRule> {
+        self.rules.iter().find(|rule| {
+            let service = rule.service.as_ref().map_or(true, |service| {
+                Pattern::new(service).map_or(false, |p| p.matches(name))
+            });
+            let registry = rule.registry.as_ref().map_or(true, |pattern| {
+                !registry.is_empty() && registry_matches(pattern, registry)
+            });
+            service && registry
+        })
+    }
+
+    // trusted keys of a rule as (key id, key location)
+    fn rule_keys(&self, rule: &TrustRule) -> Vec<(String, String)> {
+        rule.keys
+            .iter()
+            .filter_map(|key| self.keys.iter().find(|trusted| &trusted.id == key))
+            .map(|trusted| (trusted.id.clone(), trusted.path.clone()))
+            .collect()
+    }
+}
+
+impl TrustStore {
+    // evaluate the policy for a service binary and its signature, a signature is
+    // checked even when the policy does not require one
+    pub fn evaluate(
+        &self,
+        name: &str,
+        registry: &str,
+        file: &str,
+        signature: Option<&SignatureFile>,
+    ) -> Verification {
+        let rule = self.policy.rule(name, registry);
+        if rule.is_none() {
+            return match signature {
+                None => Verification::Missing,
+                Some(_) => Verification::Untrusted("no trust policy rule matches".to_string()),
+            };
+        }
+        let rule = rule.unwrap();
+        if signature.is_none() {
+            return match rule.require_signature {
+                true => Verification::Missing,
+                false => Verification::Unsigned,
+            };
+        }
+        let keys = self.policy.rule_keys(rule);
+        let mut errors = vec![];
+        for (id, source) in keys.iter() {
+            let key = self
+                .keys
+                .get(source)
+                .cloned()
+                .unwrap_or(Err(format!("key {} not loaded", source)));
+            if let Err(err) = key {
+                errors.push(format!("{} {}", id, err));
+                continue;
+            }
+            match verify_signature(&key.unwrap(), file, signature.unwrap()) {
+                Ok(true) => return Verification::Trusted(id.clone()),
+                Ok(false) => {}
+                Err(err) => errors.push(format!("{} {}", id, err.to_string().to_lowercase())),
+            }
+        }
+        let ids = keys
+            .iter()
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<String>>();
+        let mut reason = format!(
+            "signature does not match any trusted key ({})",
+            ids.join(", ")
+        );
+        if !errors.is_empty() {
+            reason = format!("{}: {}", reason, errors.join(", "));
+        }
+        Verification::Untrusted(reason)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(service: Option<&str>, registry: Option<&str>, keys: &[&str]) -> TrustRule {
+        TrustRule {
+            service: service.map(|s| s.to_string()),
+            registry: registry.map(|r| r.to_string()),
+            keys: keys.iter().map(|k| k.to_string()).collect(),
+            require_signature: true,
+        }
+    }
+
+    fn policy(rules: Vec<TrustRule>) -> TrustPolicy {
+        TrustPolicy {
+            keys: ["release", "ci"]
+                .iter()
+                .map(|id| TrustedKey {
+                    id: id.to_string(),
+                    path: format!("/nonexistent/{}.pem", id),
+                })
+                .collect(),
+            rules,
+        }
+    }
+
+    #[test]
+    fn registry_path_boundary() {
+        assert!(registry_matches("quay.io/acme", "quay.io/acme/convey:v1"));
+        assert!(registry_matches(
+            "quay.io/acme/",
+            "quay.io/acme/team/convey"
+        ));
+        assert!(registry_matches("quay.io", "quay.io/acme/convey"));
+        assert!(registry_matches(
+            "quay.io/acme/convey",
+            "quay.io/acme/convey:v1"
+        ));
+        assert!(!registry_matches(
+            "quay.io/acme",
+            "quay.io/acme-evil/convey:v1"
+        ));
+        assert!(!registry_matches(
+            "quay.io/acme/convey",
+            "quay.io/acme/convey-dev:v1"
+        ));
+        assert!(!registry_matches("quay.io", "quay.io.evil.com/acme/convey"));
+    }
+
+    #[test]
+    fn first_matching_rule_applies() {
+        let policy = policy(vec![
+            rule(Some("dev-*"), None, &["ci"]),
+            rule(None, Some("quay.io/acme"), &["release"]),
+            rule(None, None, &["release", "ci"]),
+        ]);
+        let keys = |name: &str, registry: &str| policy.rule(name, registry).unwrap().keys.clone();
+        assert_eq!(keys("dev-api", "quay.io/acme/dev-api:v1"), vec!["ci"]);
+        assert_eq!(keys("api", "quay.io/acme/api:v1"), vec!["release"]);
+        assert_eq!(keys("api", "quay.io/other/api:v1"), vec!["release", "ci"]);
+        // registry rules never match a service without a registry
+        assert_eq!(keys("api", ""), vec!["release", "ci"]);
+    }
+
+    #[test]
+    fn no_matching_rule() {
+        let store = TrustStore::new(policy(vec![rule(Some("dev-*"), None, &["ci"])]));
+        assert_eq!(
+            store.evaluate("api", "", "/nonexistent/api", None),
+            Verification::Missing
+        );
+    }
+
+    #[test]
+    fn signature_not_required() {
+        let mut optional = rule(Some("dev-*"), None, &["ci"]);
+        optional.require_signature = false;
+        let store = TrustStore::new(policy(vec![optional, rule(None, None, &["release"])]));
+        assert_eq!(
+            store.evaluate("dev-api", "", "/nonexistent/dev-api", None),
+            Verification::Unsigned
+        );
+        assert_eq!(
+            store.evaluate("api", "", "/nonexistent/api", None),
+            Verification::Missing
+        );
+    }
+
+    #[test]
+    fn signature_checked_when_not_required() {
+        let mut optional = rule(None, None, &["ci"]);
+        optional.require_signature = false;
+        let store = TrustStore::new(policy(vec![optional]));
+        let signature = SignatureFile {
+            algorithm: Default::default(),
+            payload: None,
+            signature: "c2lnbmF0dXJl".to_string(),
+        };
+        let verification = store.evaluate("api", "", "/nonexistent/api", Some(&signature));
+        assert!(matches!(verification, Verification::Untrusted(_)));
+    }
+
+    #[tokio::test]
+    async fn unknown_key_id_rejected() {
+        let dir = std::env::temp_dir().join(format!("mpm-trust-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(TRUST_POLICY_FILE).to_string_lossy().to_string();
+        let policy = "keys:\n  - id: release\n    path: release.pem\nrules:\n  - service: \"*\"\n    keys:\n      - release\n      - release.pem\n";
+        std::fs::write(&path, policy).unwrap();
+        let res = load_trust_policy(&path, "/nonexistent").await;
+        let _ = std::fs::remove_dir_all(&dir);
+        let err = res.err().unwrap().to_string();
+        assert!(err.contains("unknown key id release.pem"), "{}", err);
+    }
+}
@@ -0,0 +1,76 @@
+use crate::api::schema::{APIResponse, CommandRequest, ControllerState, DeployedService, NodeInfo};
+use schemars::gen::SchemaSettings;
+use serde_json::{json, Value};
+
+// openapi 3 document for the controller http api, generated from the schema types
+pub fn openapi() -> Value {
+    let mut generator = SchemaSettings::openapi3().into_generator();
+    let nodes = generator.subschema_for::<Vec<NodeInfo>>();
+    let services = generator.subschema_for::<Vec<DeployedService>>();
+    let state = generator.subschema_for::<ControllerState>();
+    let request = generator.subschema_for::<CommandRequest>();
+    let responses = generator.subschema_for::<Vec<APIResponse>>();
+    let schemas = generator.definitions().clone();
+
+    let command = |summary: &str| {
+        json!({
+            "post": {
+                "summary": summary,
+                "requestBody": {
+                    "required": true,
+                    "content": { "application/json": { "schema": request } }
+                },
+                "responses": {
+                    "200": {
+                        "description": "responses from the workers",
+                        "content": { "application/json": { "schema": responses } }
+                    },
+                    "400": { "description": "malformed request" },
+                    "401": { "description": "missing or invalid api token" },
+                    "503": { "description": "no ready node matches the request" },
+                    "504": {
+                        "description": "not all workers responded in time",
+                        "content": { "application/json": { "schema": responses } }
+                    }
+                }
+            }
+        })
+    };
+    let get = |summary: &str, schema: &schemars::schema::Schema| {
+        json!({
+            "get": {
+                "summary": summary,
+                "responses": {
+                    "200": {
+                        "description": "OK",
+                        "content": { "application/json": { "schema": schema } }
+                    },
+                    "401": { "description": "missing or invalid api token" }
+                }
+            }
+        })
+    };
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "microservice package manager controller",
+            "version": env!("CARGO_PKG_VERSION")
+        },
+        "paths": {
+            "/api/v1/nodes": get("list registered nodes", &nodes),
+            "/api/v1/services": get("list deployed services (filter with ?node=)", &services),
+            "/api/v1/status": get("controller state (nodes and services)", &state),
+            "/api/v1/stage": command("stage microservices on a node or all nodes"),
+            "/api/v1/start": command("start a microservice"),
+            "/api/v1/stop": command("stop a microservice"),
+        },
+        "security": [{ "apiToken": [] }],
+        "components": {
+            "schemas": schemas,
+            "securitySchemes": {
+                "apiToken": { "type": "http", "scheme": "bearer" }
+            }
+        }
+    })
+}
@@ -0,0 +1,258 @@
+use crate::api::schema::{APIParameters, APIResponse, CommandRequest, ControllerState};
+use crate::job::table::{new_job_id, JobTable};
+use crate::node::registry::NodeRegistry;
+use crate::node::token::validate_api_token;
+use crate::rest::openapi::openapi;
+use crate::workflow::handler::resolve_digests;
+use custom_logger::{debug, info, warn};
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde::Serialize;
+use std::convert::Infallible;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Sender;
+use tokio::time::{timeout_at, Instant};
+
+// default time (seconds) to wait for workers to answer a command
+const DEFAULT_TIMEOUT: u64 = 60;
+
+fn json_response<T: Serialize>(status: StatusCode, body: &T) -> Response<Full<Bytes>> {
+    let body = serde_json::to_vec(body).unwrap_or_default();
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Full::new(Bytes::from(body)))
+        .unwrap()
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<Full<Bytes>> {
+    json_response(status, &serde_json::json!({ "error": message }))
+}
+
+// send the command over the same broadcast path as the websocket clients
+// and collect the worker responses carrying the same request id
+async fn dispatch(
+    command: &str,
+    request: CommandRequest,
+    bcast_tx: Sender<String>,
+    registry: Arc<Mutex<NodeRegistry>>,
+    jobs: Arc<Mutex<JobTable>>,
+) -> Response<Full<Bytes>> {
+    let nodes = registry.lock().unwrap().ready_nodes(&request.node);
+    let expected = nodes.len();
+    if expected == 0 {
+        return error_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            &format!("no ready node matches {}", request.node),
+        );
+    }
+    // tags are resolved once on the controller so every node stages the same digest
+    let from_registry = request.from_registry.unwrap_or(false);
+    let skip_tls_verify = request.skip_tls_verify.unwrap_or(false);
+    let mut digests = None;
+    if command == "stage" && from_registry {
+        let res = resolve_digests(
+            &request.config_file,
+            skip_tls_verify,
+            request.update.unwrap_or(false),
+        )
+        .await;
+        if res.is_err() {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                &res.err().unwrap().to_string().to_lowercase(),
+            );
+        }
+        digests = Some(res.unwrap());
+    }
+    let id = new_job_id();
+    // stage runs as a job, it can also be followed with jobs wait
+    if command == "stage" {
+        jobs.lock()
+            .unwrap()
+            .create(&id, command, &request.node, nodes);
+    }
+    let api_params = APIParameters {
+        command: command.to_string(),
+        node: request.node,
+        service: request.service.unwrap_or("all".to_string()),
+        config_file: Some(request.config_file),
+        working_dir: Some(request.working_dir),
+        from_registry: Some(from_registry),
+        skip_tls_verify: Some(skip_tls_verify),
+        ip: None,
+        subnet: None,
+        registration: None,
+        id: Some(id.clone()),
+        digests,
+        insecure_allow_unsigned: request.insecure_allow_unsigned,
+        reproducible: None,
+        compression: None,
+        private_key: None,
+        api_token: None,
+    };
+    // subscribe before sending so no response is missed
+    let mut bcast_rx = bcast_tx.subscribe();
+    let message = serde_json::to_string(&api_params).unwrap();
+    if let Err(err) = bcast_tx.send(message) {
+        return error_response(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string());
+    }
+    debug!("[dispatch] {} {} sent to {} node(s)", command, id, expected);
+
+    let deadline = Instant::now() + Duration::from_secs(request.timeout.unwrap_or(DEFAULT_TIMEOUT));
+    let mut responses: Vec<APIResponse> = vec![];
+    while responses.len() < expected {
+        match timeout_at(deadline, bcast_rx.recv()).await {
+            Ok(Ok(msg)) => {
+                if let Ok(response) = serde_json::from_str::<APIResponse>(&msg) {
+                    if response.id.as_deref() == Some(id.as_str()) {
+                        responses.push(response);
+                    }
+                }
+            }
+            Ok(Err(RecvError::Lagged(count))) => {
+                warn!("[dispatch] {} skipped {} messages", id, count);
+            }
+            Ok(Err(RecvError::Closed)) => break,
+            Err(_) => {
+                warn!(
+                    "[dispatch] {} timed out ({} of {} responses)",
+                    id,
+                    responses.len(),
+                    expected
+                );
+                return json_response(StatusCode::GATEWAY_TIMEOUT, &responses);
+            }
+        }
+    }
+    json_response(StatusCode::OK, &responses)
+}
+
+// requests carry an api token (controller token create --api) as a bearer token
+fn authorize(req: &Request<Incoming>, data_dir: &str) -> Result<(), String> {
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match token {
+        Some(token) => validate_api_token(data_dir, token.trim()).map_err(|e| e.to_string()),
+        None => Err("missing bearer token".to_string()),
+    }
+}
+
+async fn route(
+    req: Request<Incoming>,
+    data_dir: String,
+    bcast_tx: Sender<String>,
+    registry: Arc<Mutex<NodeRegistry>>,
+    jobs: Arc<Mutex<JobTable>>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let path = req.uri().path().trim_end_matches('/').to_string();
+    // the openapi document is the only unauthenticated endpoint
+    if path != "/api/v1/openapi.json" {
+        if let Err(err) = authorize(&req, &data_dir) {
+            warn!("[route] {} {} rejected {}", req.method(), path, err);
+            let mut response = error_response(StatusCode::UNAUTHORIZED, &err);
+            response
+                .headers_mut()
+                .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            return Ok(response);
+        }
+    }
+    // only ?node=<name> is supported as a query parameter
+    let node = req.uri().query().and_then(|query| {
+        query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("node="))
+            .map(|node| node.to_string())
+    });
+    let response = match (req.method(), path.as_str()) {
+        (&Method::GET, "/api/v1/nodes") => {
+            let nodes = registry.lock().unwrap().nodes();
+            json_response(StatusCode::OK, &nodes)
+        }
+        (&Method::GET, "/api/v1/services") => {
+            let registry = registry.lock().unwrap();
+            let services = match node {
+                Some(node) => registry.services(&node),
+                None => registry.all_services(),
+            };
+            json_response(StatusCode::OK, &services)
+        }
+        (&Method::GET, "/api/v1/status") => {
+            let registry = registry.lock().unwrap();
+            let state = ControllerState {
+                nodes: registry.nodes(),
+                services: registry.all_services(),
+            };
+            json_response(StatusCode::OK, &state)
+        }
+        (&Method::GET, "/api/v1/openapi.json") => json_response(StatusCode::OK, &openapi()),
+        (&Method::POST, "/api/v1/stage")
+        | (&Method::POST, "/api/v1/start")
+        | (&Method::POST, "/api/v1/stop") => {
+            let command = path.rsplit('/').next().unwrap().to_string();
+            let body = match req.into_body().collect().await {
+                Ok(body) => body.to_bytes(),
+                Err(err) => return Ok(error_response(StatusCode::BAD_REQUEST, &err.to_string())),
+            };
+            match serde_json::from_slice::<CommandRequest>(&body) {
+                Ok(request) if command != "stage" && request.service.is_none() => error_response(
+                    StatusCode::BAD_REQUEST,
+                    &format!("{} requires a service", command),
+                ),
+                Ok(request) => dispatch(&command, request, bcast_tx, registry, jobs).await,
+                Err(err) => error_response(StatusCode::BAD_REQUEST, &err.to_string()),
+            }
+        }
+        _ => error_response(StatusCode::NOT_FOUND, "not found"),
+    };
+    Ok(response)
+}
+
+// http json api on the controller, commands map onto the websocket broadcast channel
+pub async fn start_api(
+    listen: String,
+    data_dir: String,
+    bcast_tx: Sender<String>,
+    registry: Arc<Mutex<NodeRegistry>>,
+    jobs: Arc<Mutex<JobTable>>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let listener = TcpListener::bind(&listen).await?;
+    info!("http api listening on : http://{}/api/v1", listen);
+    loop {
+        let (socket, addr) = listener.accept().await?;
+        debug!("new api connection from {addr:?}");
+        let data_dir = data_dir.clone();
+        let bcast_tx = bcast_tx.clone();
+        let registry = registry.clone();
+        let jobs = jobs.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| {
+                route(
+                    req,
+                    data_dir.clone(),
+                    bcast_tx.clone(),
+                    registry.clone(),
+                    jobs.clone(),
+                )
+            });
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(socket), service)
+                .await
+            {
+                debug!("api connection {addr:?} {}", err);
+            }
+        });
+    }
+}
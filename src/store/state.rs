@@ -0,0 +1,67 @@
+use crate::api::schema::ControllerState;
+use mirror_error::MirrorError;
+use std::fs;
+use std::path::Path;
+
+// json snapshot of the controller state under {data-dir}/state
+pub struct StateStore {
+    file: String,
+    pub state: ControllerState,
+}
+
+impl StateStore {
+    // load the last snapshot, an empty state is used on first start
+    pub fn load(data_dir: &str) -> Result<Self, MirrorError> {
+        let dir = format!("{}/state", data_dir);
+        let res = fs::create_dir_all(&dir);
+        if res.is_err() {
+            return Err(MirrorError::new(&format!(
+                "[state_store] creating {} {}",
+                dir,
+                res.err().unwrap().to_string().to_lowercase()
+            )));
+        }
+        let file = format!("{}/state.json", dir);
+        if !Path::new(&file).exists() {
+            return Ok(Self {
+                file,
+                state: ControllerState::default(),
+            });
+        }
+        let res = fs::read_to_string(&file);
+        if res.is_err() {
+            return Err(MirrorError::new(&format!(
+                "[state_store] reading {} {}",
+                file,
+                res.err().unwrap().to_string().to_lowercase()
+            )));
+        }
+        let res_json = serde_json::from_str::<ControllerState>(&res.unwrap());
+        if res_json.is_err() {
+            return Err(MirrorError::new(&format!(
+                "[state_store] parsing {} {}",
+                file,
+                res_json.err().unwrap().to_string().to_lowercase()
+            )));
+        }
+        Ok(Self {
+            file,
+            state: res_json.unwrap(),
+        })
+    }
+
+    // write the snapshot to a temporary file first so a crash never leaves a partial state
+    pub fn save(&self) -> Result<(), MirrorError> {
+        let json = serde_json::to_string_pretty(&self.state).unwrap();
+        let tmp = format!("{}.tmp", self.file);
+        let res = fs::write(&tmp, json).and_then(|_| fs::rename(&tmp, &self.file));
+        if res.is_err() {
+            return Err(MirrorError::new(&format!(
+                "[state_store] writing {} {}",
+                self.file,
+                res.err().unwrap().to_string().to_lowercase()
+            )));
+        }
+        Ok(())
+    }
+}
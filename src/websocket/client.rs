@@ -1,5 +1,15 @@
+use std::error::Error;
 use std::str::FromStr;
 
+use crate::api::schema::{DeployedService, Event, EventKind, Progress, TlsSettings};
+use crate::command::process::reap_services;
+use crate::config::endpoint::Endpoint;
+use crate::job::progress::ProgressReporter;
+use crate::node::credential::{registration, save_certificate};
+use crate::node::registry::HEARTBEAT_INTERVAL;
+use crate::package::create::package_options;
+use crate::package::signature::private_key_source;
+use crate::package::trust::load_trust_policy;
 use crate::workflow::handler;
 use crate::{api::schema::APIParameters, APIResponse};
 use custom_logger::*;
@@ -7,18 +17,186 @@ use futures_util::stream::StreamExt;
 use futures_util::SinkExt;
 use gethostname::gethostname;
 use http::Uri;
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio_websockets::{ClientBuilder, Message};
+use std::collections::HashMap;
+use std::fs;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader, Lines, Stdin};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+use tokio::time::{interval, sleep};
+use tokio_native_tls::native_tls;
+use tokio_websockets::{ClientBuilder, Connector, MaybeTlsStream, Message, WebSocketStream};
 
-pub async fn start_client(server_ip: String) -> Result<(), tokio_websockets::Error> {
-    let address = &format!("ws://{}:2000", server_ip);
-    let (mut ws_stream, _) = ClientBuilder::from_uri(Uri::from_str(address).unwrap())
-        .connect()
-        .await?;
+// reconnect backoff limits (seconds)
+const INITIAL_BACKOFF: u64 = 1;
+const MAX_BACKOFF: u64 = 60;
+
+// how a worker session with the controller ended
+enum SessionEnd {
+    // stdin was closed, stop the worker
+    Shutdown,
+    // the worker was registered and the connection was lost
+    Disconnected,
+    // the controller could not be reached (or dropped the connection before registration)
+    Unreachable(String),
+}
 
+// connect to the controller, over tls when the endpoint scheme is wss
+async fn connect(
+    controller: &Endpoint,
+    tls: &TlsSettings,
+) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, Box<dyn Error + Send + Sync>> {
+    let uri = Uri::from_str(&controller.url())?;
+    let (ws_stream, _) = match tls.ca.as_ref() {
+        Some(ca) if controller.is_tls() => {
+            let pem = fs::read(ca)?;
+            let connector = native_tls::TlsConnector::builder()
+                .add_root_certificate(native_tls::Certificate::from_pem(&pem)?)
+                .build()?;
+            let connector = Connector::NativeTls(connector.into());
+            ClientBuilder::from_uri(uri)
+                .connector(&connector)
+                .connect()
+                .await?
+        }
+        _ => ClientBuilder::from_uri(uri).connect().await?,
+    };
+    Ok(ws_stream)
+}
+
+pub async fn start_client(
+    controller: Endpoint,
+    tls: TlsSettings,
+    data_dir: String,
+    join_token: Option<String>,
+    key_dir: String,
+    trust_policy: String,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let address = controller.url();
     let stdin = tokio::io::stdin();
     let mut stdin = BufReader::new(stdin).lines();
-    info!("starting worker : {}", address);
+    let mut backoff = INITIAL_BACKOFF;
+
+    // reconnect with exponential backoff, supervised services keep running in the meantime
+    loop {
+        match worker_session(
+            &controller,
+            &tls,
+            &data_dir,
+            &key_dir,
+            &trust_policy,
+            join_token.clone(),
+            &mut stdin,
+        )
+        .await?
+        {
+            SessionEnd::Shutdown => return Ok(()),
+            SessionEnd::Disconnected => {
+                warn!("connection to controller {} lost", address);
+                backoff = INITIAL_BACKOFF;
+            }
+            SessionEnd::Unreachable(err) => {
+                warn!("controller {} unreachable {}", address, err.to_lowercase());
+            }
+        }
+        info!("reconnecting in {} seconds", backoff);
+        sleep(Duration::from_secs(backoff)).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+async fn worker_session(
+    controller: &Endpoint,
+    tls: &TlsSettings,
+    data_dir: &str,
+    key_dir: &str,
+    trust_policy: &str,
+    join_token: Option<String>,
+    stdin: &mut Lines<BufReader<Stdin>>,
+) -> Result<SessionEnd, Box<dyn Error + Send + Sync>> {
+    let mut ws_stream = match connect(controller, tls).await {
+        Ok(ws_stream) => ws_stream,
+        Err(err) => return Ok(SessionEnd::Unreachable(err.to_string())),
+    };
+
+    // register with the controller (join token on first enrollment, certificate after that)
+    let node = gethostname().to_string_lossy().to_string();
+    let details = registration(data_dir.to_string(), node.clone(), join_token)
+        .await
+        .map_err(|e| e.to_string())?;
+    let register = APIParameters {
+        command: "register".to_string(),
+        node: node.clone(),
+        service: "".to_string(),
+        config_file: None,
+        working_dir: None,
+        from_registry: None,
+        skip_tls_verify: None,
+        ip: None,
+        subnet: None,
+        registration: Some(details),
+        id: None,
+        digests: None,
+        insecure_allow_unsigned: None,
+        reproducible: None,
+        compression: None,
+        private_key: None,
+        api_token: None,
+    };
+    let res = ws_stream
+        .send(Message::text(serde_json::to_string(&register)?))
+        .await;
+    if let Err(err) = res {
+        return Ok(SessionEnd::Unreachable(err.to_string()));
+    }
+    match ws_stream.next().await {
+        Some(Ok(msg)) => {
+            let res = serde_json::from_str::<APIResponse>(msg.as_text().unwrap_or_default())?;
+            if res.status == "KO" {
+                return Err(res.text.into());
+            }
+            if let Some(cert) = res.credential {
+                save_certificate(data_dir.to_string(), cert).map_err(|e| e.to_string())?;
+                info!(
+                    "worker enrolled, credential stored in {}/credentials",
+                    data_dir
+                );
+            }
+        }
+        Some(Err(err)) => return Ok(SessionEnd::Unreachable(err.to_string())),
+        None => {
+            return Ok(SessionEnd::Unreachable(
+                "connection closed during registration".to_string(),
+            ))
+        }
+    }
+
+    info!("starting worker : {}", controller.url());
+    let heartbeat = serde_json::to_string(&APIParameters {
+        command: "heartbeat".to_string(),
+        node: node.clone(),
+        service: "".to_string(),
+        config_file: None,
+        working_dir: None,
+        from_registry: None,
+        skip_tls_verify: None,
+        ip: None,
+        subnet: None,
+        registration: None,
+        id: None,
+        digests: None,
+        insecure_allow_unsigned: None,
+        reproducible: None,
+        compression: None,
+        private_key: None,
+        api_token: None,
+    })?;
+    let mut ticker = interval(Duration::from_secs(HEARTBEAT_INTERVAL));
+    // responses and events sent back to the controller (json)
+    let (response_tx, mut response_rx) = mpsc::channel::<String>(16);
+    // commands running in the background, by job id (used to cancel them)
+    let mut tasks: HashMap<String, (AbortHandle, ProgressReporter)> = HashMap::new();
 
     // Continuous loop for concurrently sending and receiving messages.
     loop {
@@ -26,13 +204,10 @@ pub async fn start_client(server_ip: String) -> Result<(), tokio_websockets::Err
             incoming = ws_stream.next() => {
                 match incoming {
                     Some(Ok(msg)) => {
-                        let text = msg.as_text();
-                        if text.is_some() {
-                            let json_data = text.unwrap();
+                        if let Some(json_data) = msg.as_text() {
                             // check if its a response
-                            let api_response_result = serde_json::from_str::<APIResponse>(&json_data);
-                            if api_response_result.is_ok() {
-                                let res  = api_response_result.unwrap();
+                            let api_response_result = serde_json::from_str::<APIResponse>(json_data);
+                            if let Ok(res) = api_response_result {
                                 if res.status == "KO" {
                                     error!("{}",res.text);
                                 } else {
@@ -40,127 +215,91 @@ pub async fn start_client(server_ip: String) -> Result<(), tokio_websockets::Err
                                 }
                             } else {
                                 // if its not ok try the APIParameters
-                                let api_params: APIParameters = serde_json::from_str(&json_data).unwrap();
-                                let mut message =  APIResponse {
-                                    status: "".to_string(),
-                                    text: "".to_string(),
-                                    node: "".to_string(),
-                                    service: "".to_string(),
-                                };
-                                match api_params.command.as_str() {
-                                    "package" => {
-                                        if api_params.node == gethostname().to_string_lossy().to_string() {
-                                            let res = handler::package(
-                                                &api_params.working_dir.unwrap(),
-                                                &api_params.config_file.unwrap(),
-                                                &api_params.skip_tls_verify.unwrap(),
-                                            )
-                                            .await;
-                                            if res.is_err() {
-                                                message.status = "KO".to_string();
-                                                message.text = format!("package error {}",res.err().unwrap().to_string().to_lowercase());
-                                            } else {
-                                                message.text = format!("package completed successfully");
-                                            }
-                                        }
-                                    },
-                                    "stage" => {
-                                        let res = handler::stage(
-                                            api_params.from_registry.unwrap(),
-                                            api_params.working_dir.unwrap(),
-                                            api_params.config_file.unwrap(),
-                                            api_params.skip_tls_verify.unwrap(),
-                                        )
-                                        .await;
-                                        if res.is_err() {
-                                            message.status = "KO".to_string();
-                                            message.text = format!("staging error {}",res.err().unwrap().to_string().to_lowercase());
-                                        } else {
-                                           message.status = "OK".to_string();
-                                           message.text = format!("from message server -> staging completed successfully");
-                                        }
-                                    },
-                                    "list" => {
-                                        let res = handler::list(
-                                        )
-                                        .await;
-                                        if res.is_err() {
-                                            message.status = "KO".to_string();
-                                            message.text = format!("list error {}",res.err().unwrap().to_string().to_lowercase());
-                                        } else {
-                                            message.status = "OK".to_string();
-                                            message.service = "list".to_string();
-                                            message.node = gethostname().to_string_lossy().to_string();
-                                            message.text = res.unwrap();
-                                        }
-                                    },
-                                    "start" => {
-                                        let res = handler::start(
-                                            api_params.service.clone(),
-                                            api_params.working_dir.unwrap(),
-                                            api_params.config_file.unwrap(),
-                                        )
-                                        .await;
-                                        if res.is_err() {
-                                            message.status = "KO".to_string();
-                                            message.text = format!("{}",res.err().unwrap().to_string().to_lowercase());
-                                        } else {
-                                            message.status = "OK".to_string();
-                                            message.service = api_params.service.to_string();
-                                            message.node = gethostname().to_string_lossy().to_string();
-                                            message.text = "from message server -> started".to_string();
-                                        }
-                                    },
-                                    "stop" => {
-                                        let res = handler::stop(
-                                            api_params.service.clone(),
-                                        )
-                                        .await;
-                                        if res.is_err() {
-                                            message.status = "KO".to_string();
-                                            message.text = format!("from message server -> stop service error {}",res.err().unwrap().to_string().to_lowercase());
-                                        } else {
-                                            message.status = "OK".to_string();
-                                            message.service = api_params.service.to_string();
-                                            message.node = gethostname().to_string_lossy().to_string();
-                                            message.text = "from message server -> stopped".to_string();
-                                        }
-                                    },
-                                    "create_bridge" => {
-                                        let res = handler::bridge(
-                                            api_params.service.clone(),
-                                            api_params.ip.unwrap(),
-                                            api_params.subnet.unwrap(),
-                                        )
-                                        .await;
-                                        if res.is_err() {
-                                            message.status = "KO".to_string();
-                                            message.text = format!("from message server -> create_bridge error {}",res.err().unwrap().to_string().to_lowercase());
-                                        } else {
-                                            message.status = "OK".to_string();
-                                            message.service = api_params.service.to_string();
-                                            message.node = gethostname().to_string_lossy().to_string();
-                                            message.text = "from message server -> created_bridge successful".to_string();
-                                        }
-                                    },
-                                    &_ => {
-                                        message.status = "KO".to_string();
-                                        message.text = format!("incorrect command (not supported) {}",api_params.command);
-                                    },
+                                let res_params = serde_json::from_str::<APIParameters>(json_data);
+                                if res_params.is_err() {
+                                    debug!("ignoring message {}", json_data);
+                                    continue;
+                                }
+                                let api_params = res_params.unwrap();
+                                // only handle commands addressed to this node
+                                if api_params.node != "all" && api_params.node != node {
+                                    continue;
+                                }
+                                if api_params.command == "cancel" {
+                                    let id = api_params.id.unwrap_or_default();
+                                    if let Some((task, progress)) = tasks.remove(&id) {
+                                        progress.cancel();
+                                        task.abort();
+                                        warn!("job {} cancelled", id);
+                                    }
+                                    continue;
+                                }
+                                // run the command in the background so heartbeats keep flowing
+                                let response_tx = response_tx.clone();
+                                let id = api_params.id.clone();
+                                let progress = ProgressReporter::new(id.clone(), response_tx.clone());
+                                let key_dir = key_dir.to_string();
+                                let trust_policy = trust_policy.to_string();
+                                let job_progress = progress.clone();
+                                let task = tokio::spawn(async move {
+                                    let bridge = (api_params.command == "create_bridge")
+                                        .then(|| api_params.service.clone());
+                                    let message = handle_command(api_params, progress, &key_dir, &trust_policy).await;
+                                    let created = message.status == "OK";
+                                    let _ = response_tx.send(serde_json::to_string(&message).unwrap()).await;
+                                    if let (Some(bridge), true) = (bridge, created) {
+                                        let event = worker_event(EventKind::NetworkCreated, &bridge, None);
+                                        let _ = response_tx.send(event).await;
+                                    }
+                                });
+                                tasks.retain(|_, (task, _)| !task.is_finished());
+                                if let Some(id) = id {
+                                    tasks.insert(id, (task.abort_handle(), job_progress));
                                 }
-                                let json_response = serde_json::to_string(&message).unwrap();
-                                ws_stream.send(Message::text(json_response)).await?;
                             }
                         }
                     },
-                    Some(Err(err)) => return Err(err.into()),
-                    None => return Ok(()),
+                    Some(Err(err)) => {
+                        debug!("worker session {}", err.to_string().to_lowercase());
+                        return Ok(SessionEnd::Disconnected);
+                    }
+                    None => return Ok(SessionEnd::Disconnected),
+                }
+            }
+            Some(message) = response_rx.recv() => {
+                if ws_stream.send(Message::text(message)).await.is_err() {
+                    return Ok(SessionEnd::Disconnected);
+                }
+            }
+            _ = ticker.tick() => {
+                // report services that exited on their own
+                for (name, reason) in reap_services() {
+                    let response = APIResponse {
+                        status: "KO".to_string(),
+                        text: format!("service {} {}", name, reason),
+                        node: node.clone(),
+                        service: name.clone(),
+                        credential: None,
+                        services: Some(vec![service_status(&name, "exited")]),
+                        id: None,
+                        jobs: None,
+                    };
+                    if ws_stream.send(Message::text(serde_json::to_string(&response)?)).await.is_err() {
+                        return Ok(SessionEnd::Disconnected);
+                    }
+                }
+                if ws_stream.send(Message::text(heartbeat.clone())).await.is_err() {
+                    return Ok(SessionEnd::Disconnected);
                 }
             }
             res = stdin.next_line() => {
                 match res {
-                    Ok(None) => return Ok(()),
-                    Ok(Some(line)) => ws_stream.send(Message::text(line.to_string())).await?,
+                    Ok(None) => return Ok(SessionEnd::Shutdown),
+                    Ok(Some(line)) => {
+                        if ws_stream.send(Message::text(line.to_string())).await.is_err() {
+                            return Ok(SessionEnd::Disconnected);
+                        }
+                    }
                     Err(err) => return Err(err.into()),
                 }
             }
@@ -168,14 +307,173 @@ pub async fn start_client(server_ip: String) -> Result<(), tokio_websockets::Err
     }
 }
 
+// service status reported back to the controller
+fn service_status(name: &str, status: &str) -> DeployedService {
+    DeployedService {
+        node: gethostname().to_string_lossy().to_string(),
+        name: name.to_string(),
+        version: None,
+        digest: None,
+        status: status.to_string(),
+        updated: 0,
+    }
+}
+
+// event reported to the controller, node and timestamp are set by the controller
+fn worker_event(kind: EventKind, service: &str, message: Option<String>) -> String {
+    let event = Event {
+        kind,
+        node: gethostname().to_string_lossy().to_string(),
+        service: Some(service.to_string()),
+        message,
+        timestamp: 0,
+    };
+    serde_json::to_string(&event).unwrap()
+}
+
+// execute a command sent from the controller
+async fn handle_command(
+    api_params: APIParameters,
+    progress: ProgressReporter,
+    key_dir: &str,
+    trust_policy: &str,
+) -> APIResponse {
+    let mut message = APIResponse {
+        status: "".to_string(),
+        text: "".to_string(),
+        node: gethostname().to_string_lossy().to_string(),
+        service: "".to_string(),
+        credential: None,
+        services: None,
+        id: api_params.id.clone(),
+        jobs: None,
+    };
+    match api_params.command.as_str() {
+        "package" => {
+            let res = handler::package(
+                &api_params.working_dir.unwrap(),
+                &api_params.config_file.unwrap(),
+                &api_params.skip_tls_verify.unwrap(),
+                &private_key_source(key_dir, &api_params.private_key),
+                &package_options(
+                    api_params.reproducible.unwrap_or(false),
+                    api_params.compression,
+                ),
+                &progress,
+            )
+            .await;
+            if res.is_err() {
+                message.status = "KO".to_string();
+                message.text = format!(
+                    "package error {}",
+                    res.err().unwrap().to_string().to_lowercase()
+                );
+            } else {
+                message.status = "OK".to_string();
+                message.service = "package".to_string();
+                message.text = format!("package completed successfully");
+            }
+        }
+        "stage" => {
+            let res = match load_trust_policy(trust_policy, key_dir).await {
+                Ok(policy) => {
+                    handler::stage(
+                        api_params.from_registry.unwrap(),
+                        api_params.working_dir.unwrap(),
+                        api_params.config_file.unwrap(),
+                        api_params.skip_tls_verify.unwrap(),
+                        &api_params.digests.clone().unwrap_or_default(),
+                        &policy,
+                        api_params.insecure_allow_unsigned.unwrap_or(false),
+                        &progress,
+                    )
+                    .await
+                }
+                Err(err) => Err(err),
+            };
+            if res.is_err() {
+                message.status = "KO".to_string();
+                message.text = format!(
+                    "staging error {}",
+                    res.err().unwrap().to_string().to_lowercase()
+                );
+            } else {
+                message.status = "OK".to_string();
+                message.service = "stage".to_string();
+                message.node = gethostname().to_string_lossy().to_string();
+                message.text = format!("from message server -> staging completed successfully");
+                message.services = Some(res.unwrap());
+            }
+        }
+        "start" => {
+            let res = handler::start(
+                api_params.service.clone(),
+                api_params.working_dir.unwrap(),
+                api_params.config_file.unwrap(),
+            )
+            .await;
+            if res.is_err() {
+                message.status = "KO".to_string();
+                message.text = format!("{}", res.err().unwrap().to_string().to_lowercase());
+                message.services = Some(vec![service_status(&api_params.service, "failed")]);
+            } else {
+                message.status = "OK".to_string();
+                message.service = api_params.service.to_string();
+                message.node = gethostname().to_string_lossy().to_string();
+                message.text = "from message server -> started".to_string();
+                message.services = Some(vec![service_status(&api_params.service, "running")]);
+            }
+        }
+        "stop" => {
+            let res = handler::stop(api_params.service.clone()).await;
+            if res.is_err() {
+                message.status = "KO".to_string();
+                message.text = format!(
+                    "from message server -> stop service error {}",
+                    res.err().unwrap().to_string().to_lowercase()
+                );
+            } else {
+                message.status = "OK".to_string();
+                message.service = api_params.service.to_string();
+                message.node = gethostname().to_string_lossy().to_string();
+                message.text = "from message server -> stopped".to_string();
+                message.services = Some(vec![service_status(&api_params.service, "stopped")]);
+            }
+        }
+        "create_bridge" => {
+            let res = handler::bridge(
+                api_params.service.clone(),
+                api_params.ip.unwrap(),
+                api_params.subnet.unwrap(),
+            )
+            .await;
+            if res.is_err() {
+                message.status = "KO".to_string();
+                message.text = format!(
+                    "from message server -> create_bridge error {}",
+                    res.err().unwrap().to_string().to_lowercase()
+                );
+            } else {
+                message.status = "OK".to_string();
+                message.service = api_params.service.to_string();
+                message.node = gethostname().to_string_lossy().to_string();
+                message.text = "from message server -> created_bridge successful".to_string();
+            }
+        }
+        &_ => {
+            message.status = "KO".to_string();
+            message.text = format!("incorrect command (not supported) {}", api_params.command);
+        }
+    }
+    message
+}
+
 pub async fn send_message(
     message: String,
-    server_ip: String,
-) -> Result<(), tokio_websockets::Error> {
-    let address = &format!("ws://{}:2000", server_ip);
-    let (mut ws_stream, _) = ClientBuilder::from_uri(Uri::from_str(address).unwrap())
-        .connect()
-        .await?;
+    controller: &Endpoint,
+    tls: &TlsSettings,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut ws_stream = connect(controller, tls).await?;
 
     ws_stream.send(Message::text(message)).await?;
     tokio::select! {
@@ -183,7 +481,11 @@ pub async fn send_message(
             match incoming {
                 Some(Ok(msg)) => {
                     if let Some(text) = msg.as_text() {
-                        debug!("from server: {}", text);
+                        match serde_json::from_str::<APIResponse>(text) {
+                            Ok(res) if res.status == "KO" => error!("{}", res.text),
+                            Ok(res) => info!("{}", res.text),
+                            Err(_) => debug!("from server: {}", text),
+                        }
                     }
                 }
                 Some(Err(err)) => return Err(err.into()),
@@ -194,3 +496,126 @@ pub async fn send_message(
     ws_stream.close().await?;
     Ok(())
 }
+
+// print controller events, the history first and then new events when following
+pub async fn stream_events(
+    controller: &Endpoint,
+    tls: &TlsSettings,
+    follow: bool,
+    json: bool,
+    api_token: &Option<String>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut ws_stream = connect(controller, tls).await?;
+    let subscribe = APIParameters {
+        command: "events".to_string(),
+        node: "all".to_string(),
+        service: "".to_string(),
+        config_file: None,
+        working_dir: None,
+        from_registry: None,
+        skip_tls_verify: None,
+        ip: None,
+        subnet: None,
+        registration: None,
+        id: None,
+        digests: None,
+        insecure_allow_unsigned: None,
+        reproducible: None,
+        compression: None,
+        private_key: None,
+        api_token: api_token.clone(),
+    };
+    ws_stream
+        .send(Message::text(serde_json::to_string(&subscribe)?))
+        .await?;
+    while let Some(msg) = ws_stream.next().await {
+        let msg = msg?;
+        let text = match msg.as_text() {
+            Some(text) => text,
+            None => continue,
+        };
+        if let Ok(event) = serde_json::from_str::<Event>(text) {
+            if json {
+                println!("{}", text);
+            } else {
+                let kind = serde_json::to_string(&event.kind)?;
+                println!(
+                    "{} {:<30} {:<20} {} {}",
+                    event.timestamp,
+                    kind.trim_matches('"'),
+                    event.node,
+                    event.service.unwrap_or_default(),
+                    event.message.unwrap_or_default()
+                );
+            }
+        } else if let Ok(res) = serde_json::from_str::<APIResponse>(text) {
+            if res.service == "events" && !follow {
+                break;
+            }
+        }
+    }
+    ws_stream.close().await?;
+    Ok(())
+}
+
+// send a jobs command to the controller and return its reply,
+// progress messages are printed while waiting for a job
+pub async fn job_request(
+    command: &str,
+    id: Option<String>,
+    controller: &Endpoint,
+    tls: &TlsSettings,
+    api_token: &Option<String>,
+) -> Result<APIResponse, Box<dyn Error + Send + Sync>> {
+    let mut ws_stream = connect(controller, tls).await?;
+    let request = APIParameters {
+        command: command.to_string(),
+        node: "all".to_string(),
+        service: "".to_string(),
+        config_file: None,
+        working_dir: None,
+        from_registry: None,
+        skip_tls_verify: None,
+        ip: None,
+        subnet: None,
+        registration: None,
+        id,
+        digests: None,
+        insecure_allow_unsigned: None,
+        reproducible: None,
+        compression: None,
+        private_key: None,
+        api_token: api_token.clone(),
+    };
+    ws_stream
+        .send(Message::text(serde_json::to_string(&request)?))
+        .await?;
+    while let Some(msg) = ws_stream.next().await {
+        let msg = msg?;
+        let text = match msg.as_text() {
+            Some(text) => text,
+            None => continue,
+        };
+        if let Ok(progress) = serde_json::from_str::<Progress>(text) {
+            match progress.bytes {
+                Some(bytes) => info!(
+                    "{} {} {} ({} bytes)",
+                    progress.node,
+                    progress.service.unwrap_or_default(),
+                    progress.message,
+                    bytes
+                ),
+                None => info!(
+                    "{} {} {}",
+                    progress.node,
+                    progress.service.unwrap_or_default(),
+                    progress.message
+                ),
+            }
+        } else if let Ok(res) = serde_json::from_str::<APIResponse>(text) {
+            let _ = ws_stream.close().await;
+            return Ok(res);
+        }
+    }
+    Err("connection closed by the controller".into())
+}
@@ -1,16 +1,420 @@
-use custom_logger::{debug, info};
+use crate::api::schema::{
+    APIParameters, APIResponse, Event, Job, JobStatus, Progress, TlsSettings,
+};
+use crate::config::endpoint::Endpoint;
+use crate::event::bus::EventBus;
+use crate::job::table::{new_job_id, JobTable};
+use crate::node::credential::{load_or_create_ca, register};
+use crate::node::registry::{NodeRegistry, HEARTBEAT_INTERVAL};
+use crate::node::token::validate_api_token;
+use crate::rest::server::start_api;
+use crate::store::state::StateStore;
+use custom_logger::{debug, error, info, warn};
 use futures_util::sink::SinkExt;
 use futures_util::stream::StreamExt;
 use std::error::Error;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::broadcast::{channel, Sender};
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::{channel, Receiver, Sender};
+use tokio::time::interval;
+use tokio_native_tls::{native_tls, TlsAcceptor};
 use tokio_websockets::{Message, ServerBuilder, WebSocketStream};
 
-async fn handle_connection(
-    mut ws_stream: WebSocketStream<TcpStream>,
+// messages buffered for each session (commands, responses and job progress)
+const BROADCAST_CAPACITY: usize = 1024;
+
+async fn register_worker(data_dir: String, params: &APIParameters) -> APIResponse {
+    let mut response = APIResponse {
+        status: "OK".to_string(),
+        node: params.node.clone(),
+        service: "register".to_string(),
+        text: "worker registered".to_string(),
+        credential: None,
+        services: None,
+        id: None,
+        jobs: None,
+    };
+    match register(data_dir, params).await {
+        Ok(cert) => {
+            info!("worker {} registered", params.node);
+            response.credential = cert;
+        }
+        Err(err) => {
+            warn!("worker {} rejected {}", params.node, err.to_string());
+            response.status = "KO".to_string();
+            response.text = format!("registration failed {}", err.to_string().to_lowercase());
+        }
+    }
+    response
+}
+
+// answer the list command from the controller node registry
+fn list_nodes(registry: &Arc<Mutex<NodeRegistry>>) -> APIResponse {
+    let registry = registry.lock().unwrap();
+    let mut lines = vec![];
+    for node in registry.nodes() {
+        lines.push(format!(
+            "{} {:?} (last seen {})",
+            node.name, node.status, node.last_seen
+        ));
+        for service in registry.services(&node.name) {
+            lines.push(format!(
+                "  {} {} {} {}",
+                service.name,
+                service.version.unwrap_or("-".to_string()),
+                service.status,
+                service.digest.unwrap_or("-".to_string())
+            ));
+        }
+    }
+    APIResponse {
+        status: "OK".to_string(),
+        node: "all".to_string(),
+        service: "list".to_string(),
+        text: format!("list nodes ->\n{}", lines.join("\n")),
+        credential: None,
+        services: None,
+        id: None,
+        jobs: None,
+    }
+}
+
+// replay the event history, then forward new events until the subscriber disconnects
+async fn stream_events<S: AsyncRead + AsyncWrite + Unpin>(
+    ws_stream: &mut WebSocketStream<S>,
+    events: &EventBus,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (history, mut events_rx) = events.subscribe();
+    for event in history {
+        ws_stream
+            .send(Message::text(serde_json::to_string(&event)?))
+            .await?;
+    }
+    // tells the client the history has been sent (it may disconnect unless following)
+    let marker = APIResponse {
+        status: "OK".to_string(),
+        node: "all".to_string(),
+        service: "events".to_string(),
+        text: "end of event history".to_string(),
+        credential: None,
+        services: None,
+        id: None,
+        jobs: None,
+    };
+    ws_stream
+        .send(Message::text(serde_json::to_string(&marker)?))
+        .await?;
+    loop {
+        tokio::select! {
+            incoming = ws_stream.next() => {
+                match incoming {
+                    Some(Ok(_)) => continue,
+                    Some(Err(err)) => return Err(err.into()),
+                    None => return Ok(()),
+                }
+            }
+            event = events_rx.recv() => {
+                match event {
+                    Ok(event) => {
+                        ws_stream
+                            .send(Message::text(serde_json::to_string(&event)?))
+                            .await?;
+                    }
+                    Err(RecvError::Lagged(count)) => {
+                        warn!("events subscriber lagging, skipped {} events", count);
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                }
+            }
+        }
+    }
+}
+
+fn job_response(status: &str, text: String, id: Option<String>, jobs: Vec<Job>) -> APIResponse {
+    APIResponse {
+        status: status.to_string(),
+        node: "all".to_string(),
+        service: "jobs".to_string(),
+        text,
+        credential: None,
+        services: None,
+        id,
+        jobs: Some(jobs),
+    }
+}
+
+// stage and package run as jobs, the job is created before the command is sent
+// to the workers so their progress and results can be tracked
+fn submit_job(
+    mut params: APIParameters,
+    bcast_tx: &Sender<String>,
+    registry: &Arc<Mutex<NodeRegistry>>,
+    jobs: &Arc<Mutex<JobTable>>,
+) -> Result<APIResponse, Box<dyn Error + Send + Sync>> {
+    let nodes = registry.lock().unwrap().ready_nodes(&params.node);
+    if nodes.is_empty() {
+        return Ok(job_response(
+            "KO",
+            format!("no ready node matches {}", params.node),
+            None,
+            vec![],
+        ));
+    }
+    let id = params.id.clone().unwrap_or(new_job_id());
+    let job = jobs
+        .lock()
+        .unwrap()
+        .create(&id, &params.command, &params.node, nodes);
+    params.id = Some(id.clone());
+    bcast_tx.send(serde_json::to_string(&params)?)?;
+    info!("job {} ({}) submitted to {}", id, job.command, job.node);
+    Ok(job_response(
+        "OK",
+        format!("job {} created (follow it with jobs wait {})", id, id),
+        Some(id),
+        vec![job],
+    ))
+}
+
+fn cancel_job(
+    id: &str,
+    bcast_tx: &Sender<String>,
+    jobs: &Arc<Mutex<JobTable>>,
+) -> Result<APIResponse, Box<dyn Error + Send + Sync>> {
+    let res = jobs.lock().unwrap().cancel(id);
+    let job = match res {
+        Ok(job) => job,
+        Err(err) => {
+            return Ok(job_response(
+                "KO",
+                err.to_string().to_lowercase(),
+                Some(id.to_string()),
+                vec![],
+            ))
+        }
+    };
+    let cancel = APIParameters {
+        command: "cancel".to_string(),
+        node: job.node.clone(),
+        service: "".to_string(),
+        config_file: None,
+        working_dir: None,
+        from_registry: None,
+        skip_tls_verify: None,
+        ip: None,
+        subnet: None,
+        registration: None,
+        id: Some(id.to_string()),
+        digests: None,
+        insecure_allow_unsigned: None,
+        reproducible: None,
+        compression: None,
+        private_key: None,
+        api_token: None,
+    };
+    bcast_tx.send(serde_json::to_string(&cancel)?)?;
+    Ok(job_response(
+        "OK",
+        format!("job {} cancelled", id),
+        Some(id.to_string()),
+        vec![job],
+    ))
+}
+
+// forward the progress of a job until it is no longer running
+async fn wait_job<S: AsyncRead + AsyncWrite + Unpin>(
+    ws_stream: &mut WebSocketStream<S>,
+    bcast_rx: &mut Receiver<String>,
+    jobs: &Arc<Mutex<JobTable>>,
+    id: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // jobs can also finish without a message (a node disconnecting)
+    let mut ticker = interval(Duration::from_secs(1));
+    loop {
+        let job = jobs.lock().unwrap().get(id);
+        let response = match job {
+            None => Some(job_response(
+                "KO",
+                format!("job {} not found", id),
+                Some(id.to_string()),
+                vec![],
+            )),
+            Some(job) if job.status != JobStatus::Running => {
+                let status = match job.status {
+                    JobStatus::Completed => "OK",
+                    _ => "KO",
+                };
+                Some(job_response(
+                    status,
+                    format!("job {} {:?}", id, job.status).to_lowercase(),
+                    Some(id.to_string()),
+                    vec![job],
+                ))
+            }
+            Some(_) => None,
+        };
+        if let Some(response) = response {
+            ws_stream
+                .send(Message::text(serde_json::to_string(&response)?))
+                .await?;
+            return Ok(());
+        }
+        tokio::select! {
+            incoming = ws_stream.next() => {
+                match incoming {
+                    Some(Ok(_)) => continue,
+                    Some(Err(err)) => return Err(err.into()),
+                    None => return Ok(()),
+                }
+            }
+            msg = bcast_rx.recv() => {
+                match msg {
+                    Ok(msg) => {
+                        if let Ok(progress) = serde_json::from_str::<Progress>(&msg) {
+                            if progress.job == id {
+                                ws_stream.send(Message::text(msg)).await?;
+                            }
+                        }
+                    }
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return Ok(()),
+                }
+            }
+            _ = ticker.tick() => {}
+        }
+    }
+}
+
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    ws_stream: WebSocketStream<S>,
     bcast_tx: Sender<String>,
+    data_dir: String,
+    registry: Arc<Mutex<NodeRegistry>>,
+    events: EventBus,
+    jobs: Arc<Mutex<JobTable>>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut session = None;
+    let res = serve_connection(
+        ws_stream,
+        bcast_tx,
+        data_dir,
+        registry.clone(),
+        events,
+        jobs.clone(),
+        &mut session,
+    )
+    .await;
+    // mark the worker NotReady as soon as its connection goes away
+    if let Some((node, id)) = session {
+        warn!("worker {} disconnected", node);
+        registry.lock().unwrap().disconnect(&node, id);
+        jobs.lock().unwrap().node_lost(&node);
+    }
+    res
+}
+
+async fn serve_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    mut ws_stream: WebSocketStream<S>,
+    bcast_tx: Sender<String>,
+    data_dir: String,
+    registry: Arc<Mutex<NodeRegistry>>,
+    events: EventBus,
+    jobs: Arc<Mutex<JobTable>>,
+    session: &mut Option<(String, u64)>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     let mut bcast_rx = bcast_tx.subscribe();
+    // set once a worker has registered, only registered workers receive commands
+    let mut node: Option<String> = None;
+
+    // the first message tells us if this is a worker registering or a cli client
+    match ws_stream.next().await {
+        Some(Ok(msg)) => {
+            if let Some(text) = msg.as_text() {
+                match serde_json::from_str::<APIParameters>(text) {
+                    Ok(params) if params.command == "register" => {
+                        let response = register_worker(data_dir, &params).await;
+                        let registered = response.status == "OK";
+                        ws_stream
+                            .send(Message::text(serde_json::to_string(&response)?))
+                            .await?;
+                        if !registered {
+                            ws_stream.close().await?;
+                            return Ok(());
+                        }
+                        let id = registry.lock().unwrap().register(&params.node);
+                        *session = Some((params.node.clone(), id));
+                        node = Some(params.node);
+                    }
+                    Ok(mut params) => {
+                        // cli clients must present an api token before any command runs
+                        let token = params.api_token.take().unwrap_or_default();
+                        if let Err(err) = validate_api_token(&data_dir, &token) {
+                            warn!("rejected {} command {}", params.command, err);
+                            let response =
+                                job_response("KO", format!("unauthorized {}", err), None, vec![]);
+                            ws_stream
+                                .send(Message::text(serde_json::to_string(&response)?))
+                                .await?;
+                            ws_stream.close().await?;
+                            return Ok(());
+                        }
+                        match params.command.as_str() {
+                            "events" => {
+                                return stream_events(&mut ws_stream, &events).await;
+                            }
+                            "stage" | "package" => {
+                                let response = submit_job(params, &bcast_tx, &registry, &jobs)?;
+                                ws_stream
+                                    .send(Message::text(serde_json::to_string(&response)?))
+                                    .await?;
+                            }
+                            "jobs_list" => {
+                                let list = jobs.lock().unwrap().list();
+                                let response =
+                                    job_response("OK", format!("{} jobs", list.len()), None, list);
+                                ws_stream
+                                    .send(Message::text(serde_json::to_string(&response)?))
+                                    .await?;
+                                return Ok(());
+                            }
+                            "jobs_wait" => {
+                                let id = params.id.unwrap_or_default();
+                                return wait_job(&mut ws_stream, &mut bcast_rx, &jobs, &id).await;
+                            }
+                            "jobs_cancel" => {
+                                let response =
+                                    cancel_job(&params.id.unwrap_or_default(), &bcast_tx, &jobs)?;
+                                ws_stream
+                                    .send(Message::text(serde_json::to_string(&response)?))
+                                    .await?;
+                                return Ok(());
+                            }
+                            "list" => {
+                                let response = list_nodes(&registry);
+                                ws_stream
+                                    .send(Message::text(serde_json::to_string(&response)?))
+                                    .await?;
+                            }
+                            _ => {
+                                bcast_tx.send(serde_json::to_string(&params)?)?;
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        warn!("rejected message {}", err.to_string().to_lowercase());
+                        ws_stream.close().await?;
+                        return Ok(());
+                    }
+                }
+            }
+        }
+        Some(Err(err)) => return Err(err.into()),
+        None => return Ok(()),
+    }
 
     loop {
         tokio::select! {
@@ -18,6 +422,39 @@ async fn handle_connection(
                 match incoming {
                     Some(Ok(msg)) => {
                         if let Some(text) = msg.as_text() {
+                            // heartbeats are consumed by the controller
+                            if let Ok(params) = serde_json::from_str::<APIParameters>(text) {
+                                if params.command == "heartbeat" {
+                                    if let Some(name) = node.as_ref() {
+                                        registry.lock().unwrap().heartbeat(name);
+                                    }
+                                    continue;
+                                }
+                                // cli sessions only send a command as their first message
+                                if node.is_none() {
+                                    continue;
+                                }
+                            }
+                            // events sent by a worker are published, not broadcast
+                            if let (Some(name), Ok(mut event)) = (node.as_ref(), serde_json::from_str::<Event>(text)) {
+                                event.node = name.clone();
+                                events.publish(event);
+                                continue;
+                            }
+                            // progress of a job run by a worker
+                            if let (Some(_), Ok(progress)) = (node.as_ref(), serde_json::from_str::<Progress>(text)) {
+                                jobs.lock().unwrap().progress(&progress);
+                            }
+                            // record the services and job results reported by a worker
+                            if let (Some(name), Ok(response)) = (node.as_ref(), serde_json::from_str::<APIResponse>(text)) {
+                                if let Some(job) = jobs.lock().unwrap().record(name, &response) {
+                                    info!("job {} ({}) {:?}", job.id, job.command, job.status);
+                                }
+                                if let Some(services) = response.services {
+                                    let message = (response.status == "KO").then_some(response.text);
+                                    registry.lock().unwrap().record_services(name, services, message);
+                                }
+                            }
                             bcast_tx.send(text.into())?;
                         }
                     }
@@ -26,25 +463,112 @@ async fn handle_connection(
                 }
             }
             msg = bcast_rx.recv() => {
-                ws_stream.send(Message::text(msg?)).await?;
+                // a slow session skips messages instead of being dropped
+                let msg = match msg {
+                    Ok(msg) => msg,
+                    Err(RecvError::Lagged(count)) => {
+                        warn!("session lagged, skipped {} messages", count);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                };
+                // commands are only forwarded to enrolled workers
+                if node.is_none() && serde_json::from_str::<APIParameters>(&msg).is_ok() {
+                    continue;
+                }
+                // job progress is only of interest to cli and api sessions
+                if node.is_some() && serde_json::from_str::<Progress>(&msg).is_ok() {
+                    continue;
+                }
+                ws_stream.send(Message::text(msg)).await?;
             }
         }
     }
 }
 
-pub async fn start_server(server_ip: String) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let (bcast_tx, _) = channel(16);
-    let address = &format!("{}:2000", server_ip);
-    let listener = TcpListener::bind(address.clone()).await?;
-    info!("listening on (address and port) : {}", address);
+// tls acceptor for wss, the key must be in pkcs8 pem format
+fn tls_acceptor(tls: &TlsSettings) -> Result<TlsAcceptor, Box<dyn Error + Send + Sync>> {
+    let (cert, key) = match (tls.cert.as_ref(), tls.key.as_ref()) {
+        (Some(cert), Some(key)) => (fs::read(cert)?, fs::read(key)?),
+        _ => return Err("listening on wss requires a tls certificate and key".into()),
+    };
+    let identity = native_tls::Identity::from_pkcs8(&cert, &key)?;
+    let acceptor = native_tls::TlsAcceptor::new(identity)?;
+    Ok(TlsAcceptor::from(acceptor))
+}
+
+pub async fn start_server(
+    listen: Endpoint,
+    tls: TlsSettings,
+    data_dir: String,
+    api_listen: Option<String>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // make sure the certificate authority exists before workers enroll
+    load_or_create_ca(data_dir.clone())
+        .await
+        .map_err(|e| e.to_string())?;
+    let acceptor = match listen.is_tls() {
+        true => Some(tls_acceptor(&tls)?),
+        false => None,
+    };
+    let store = StateStore::load(&data_dir).map_err(|e| e.to_string())?;
+    let (bcast_tx, _) = channel(BROADCAST_CAPACITY);
+    let events = EventBus::new();
+    let registry = Arc::new(Mutex::new(NodeRegistry::new(store, events.clone())));
+    let jobs = Arc::new(Mutex::new(JobTable::new()));
+
+    // mark workers that stopped sending heartbeats as NotReady
+    let expiry = registry.clone();
+    let expiry_jobs = jobs.clone();
+    tokio::spawn(async move {
+        let mut ticker = interval(Duration::from_secs(HEARTBEAT_INTERVAL));
+        loop {
+            ticker.tick().await;
+            let expired = expiry.lock().unwrap().expire();
+            for node in expired {
+                warn!("worker {} missed heartbeats, marked NotReady", node);
+                expiry_jobs.lock().unwrap().node_lost(&node);
+            }
+        }
+    });
+
+    // the http api shares the broadcast channel and registry with the websocket server
+    if let Some(api_listen) = api_listen {
+        let data_dir = data_dir.clone();
+        let bcast_tx = bcast_tx.clone();
+        let registry = registry.clone();
+        let jobs = jobs.clone();
+        tokio::spawn(async move {
+            if let Err(err) = start_api(api_listen, data_dir, bcast_tx, registry, jobs).await {
+                error!("http api {}", err.to_string().to_lowercase());
+            }
+        });
+    }
+
+    let listener = TcpListener::bind(listen.address()).await?;
+    info!("listening on (address and port) : {}", listen.url());
     loop {
         let (socket, addr) = listener.accept().await?;
         debug!("new connection from {addr:?}");
         let bcast_tx = bcast_tx.clone();
+        let data_dir = data_dir.clone();
+        let registry = registry.clone();
+        let acceptor = acceptor.clone();
+        let events = events.clone();
+        let jobs = jobs.clone();
         tokio::spawn(async move {
             // Wrap the raw TCP stream into a websocket.
-            let ws_stream = ServerBuilder::new().accept(socket).await?;
-            handle_connection(ws_stream, bcast_tx).await
+            match acceptor {
+                Some(acceptor) => {
+                    let tls_stream = acceptor.accept(socket).await?;
+                    let ws_stream = ServerBuilder::new().accept(tls_stream).await?;
+                    handle_connection(ws_stream, bcast_tx, data_dir, registry, events, jobs).await
+                }
+                None => {
+                    let ws_stream = ServerBuilder::new().accept(socket).await?;
+                    handle_connection(ws_stream, bcast_tx, data_dir, registry, events, jobs).await
+                }
+            }
         });
     }
 }
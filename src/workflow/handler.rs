@@ -1,202 +1,384 @@
 use crate::api::schema::*;
 use crate::command::process::{start_service, stop_service};
 use crate::common::utils::*;
+use crate::config::lock::{load_lock, save_lock};
 use crate::config::read::*;
+use crate::job::progress::ProgressReporter;
 use crate::network::namespace::*;
+use crate::oci::layout::{archive_layout, blob_path, verify_blob, verify_manifest};
+use crate::oci::reference::{parse_reference, Reference};
+use crate::oci::registry::{
+    find_referrers, get_blob, get_manifest, push_blob, push_referrer, put_manifest, repository_url,
+    BlobPush, OCI_INDEX, OCI_LAYER_GZIP, OCI_LAYER_TAR, OCI_LAYER_ZSTD,
+};
 use crate::package::create::*;
 use crate::package::signature::*;
+use crate::package::trust::{TrustStore, Verification};
 use custom_logger::*;
 use flate2::read::GzDecoder;
 use gethostname::gethostname;
-use local_ip_address::linux::local_ip;
 use mirror_auth::{get_token, ImplTokenInterface};
-use mirror_copy::{
-    DownloadImageInterface, ImplDownloadImageInterface, ImplUploadImageInterface, ManifestType,
-    UploadImageInterface,
-};
+use mirror_copy::{ImplUploadImageInterface, ManifestType, UploadImageInterface};
 use mirror_error::MirrorError;
-use mirror_utils::{fs_handler, ImageReference};
+use mirror_utils::fs_handler;
+use sha256::digest;
+use std::collections::HashMap;
 use std::fs;
 use std::fs::File;
-use std::process;
+use std::io::Read;
 use tar::Archive;
 
 pub async fn package(
     working_dir: &str,
     config_file: &str,
     skip_tls_verify: &bool,
+    private_key: &str,
+    options: &PackageOptions,
+    progress: &ProgressReporter,
 ) -> Result<(), MirrorError> {
     //fs_handler(format!("{}/generated", working_dir), "remove_dir", None).await?;
     fs_handler(format!("{}/generated", working_dir), "create_dir", None).await?;
     fs_handler(format!("{}/artifacts", working_dir), "create_dir", None).await?;
+    let signature_dir = signature_dir(working_dir);
+    // the key is read once, an fd:<N> source can only be read once
+    let private_key = load_private_key(private_key)?;
     let config = load_config(config_file.to_string()).await?;
-    let sc = parse_yaml_config(config)?;
+    let sc = resolve_service_paths(parse_yaml_config(config)?, config_file);
     debug!("working-dir {}", working_dir);
     debug!("microservices struct {:#?}", sc);
     for service in sc.spec.services.iter() {
-        // first sign each artifact
-        let res = sign_artifact(
-            service.name.clone(),
-            format!("{}/{}", service.binary_path.clone(), service.name.clone()),
-        )
-        .await;
+        progress.check_cancelled()?;
+        let res = create_signed_artifact(working_dir, service, options).await;
         if res.is_err() {
-            error!(
-                "[package] signing binary {} {}",
-                service.name.clone(),
-                res.err().as_ref().unwrap().to_string().to_lowercase()
-            );
-            process::exit(1);
-        }
-        let res = create_signed_artifact(service.name.clone(), service.binary_path.clone()).await;
-        if res.is_err() {
-            error!(
+            return Err(MirrorError::new(&format!(
                 "[package] creating package {} {}",
                 service.name.clone(),
                 res.as_ref().err().unwrap().to_string().to_ascii_lowercase()
-            );
-            process::exit(1);
+            )));
         } else {
             info!(
-                "[package] artifacts created in folder generated/{}",
-                service.name
+                "[package] artifacts created in folder {}/generated/{}",
+                working_dir, service.name
             );
         }
-        // tar each oci image
-        let tar_file = File::create(format!(
-            "{}/artifacts/{}.pkg",
-            working_dir,
-            service.name.clone()
-        ))
-        .unwrap();
-
-        let mut tar_m = tar::Builder::new(tar_file);
+        let data = fs_handler(
+            format!("{}/generated/{}/index.json", working_dir, service.name),
+            "read",
+            None,
+        )
+        .await?;
+        let res_index = serde_json::from_str(&data);
+        if res_index.is_err() {
+            console_icon_err();
+            return Err(MirrorError::new(&format!(
+                "[package] parsing index.json {}",
+                res_index.err().unwrap().to_string().to_lowercase()
+            )));
+        }
+        let index: OCIIndex = res_index.unwrap();
+        // each platform binary is signed with the service name, version and the digest of its
+        // manifest, the signature is a referrer of the manifest (stored in the layout with the image)
+        progress.report(&service.name, "signing binary", None).await;
+        let layout_dir = format!("{}/generated/{}", working_dir, service.name);
+        let platforms = service_platforms(service);
+        let mut referrers = vec![];
+        for platform in platforms.iter() {
+            let signature_name = match platforms.len() {
+                1 => service.name.clone(),
+                _ => format!("{}-{}", service.name, platform.architecture),
+            };
+            let entry = index.manifests.iter().find(|entry| {
+                platforms.len() == 1
+                    || entry.platform.as_ref().map(|p| &p.architecture)
+                        == Some(&platform.architecture)
+            });
+            if entry.is_none() {
+                return Err(MirrorError::new(&format!(
+                    "[package] no manifest for {} {}",
+                    service.name, platform.architecture
+                )));
+            }
+            let entry = entry.unwrap();
+            // the payload names the service, only the signature file is per platform
+            let res = sign_artifact(
+                &private_key,
+                &signature_path(&signature_dir, &signature_name),
+                service.name.clone(),
+                format!("{}/{}", platform.binary_path, service.name),
+                Some(service.version.clone()),
+                Some(entry.digest.clone()),
+            )
+            .await;
+            if res.is_err() {
+                return Err(MirrorError::new(&format!(
+                    "[package] signing binary {} {} {}",
+                    service.name.clone(),
+                    platform.architecture,
+                    res.err().as_ref().unwrap().to_string().to_lowercase()
+                )));
+            }
+            let referrer = create_signature_referrer(
+                &layout_dir,
+                &signature_path(&signature_dir, &signature_name),
+                entry,
+            )
+            .await;
+            if referrer.is_err() {
+                return Err(MirrorError::new(&format!(
+                    "[package] creating signature referrer {} {}",
+                    signature_name,
+                    referrer.err().unwrap().to_string().to_lowercase()
+                )));
+            }
+            referrers.push((entry.digest.clone(), referrer.unwrap()));
+        }
+        // the layout index also lists the referrers so the .pkg carries the signatures,
+        // the image index pushed to the registry only lists the image manifests
+        let mut layout_index = index.clone();
+        layout_index
+            .manifests
+            .extend(referrers.iter().map(|(_, referrer)| referrer.clone()));
+        fs_handler(
+            format!("{}/index.json", layout_dir),
+            "write",
+            Some(serde_json::to_string(&layout_index).unwrap()),
+        )
+        .await?;
+        // archive each oci image layout (oci-layout, index.json and blobs)
         info!("  building artifacts for {}", service.name.clone());
-        tar_m
-            .append_dir_all(".", format!("generated/{}", service.name.clone()))
-            .unwrap();
-        tar_m.finish().expect("should flush manifest contents");
+        progress
+            .report(&service.name, "building artifacts", None)
+            .await;
+        archive_layout(
+            &format!("{}/generated/{}", working_dir, service.name),
+            &format!("{}/artifacts/{}.pkg", working_dir, service.name),
+        )?;
 
-        let parts = service.registry.split("/").collect::<Vec<&str>>();
-        let (name, version) = parts[3].split_once(":").unwrap();
-        let img_ref = ImageReference {
-            registry: parts[0].to_string(),
-            namespace: format!("{}/{}", parts[1], parts[2]),
-            name: name.to_string(),
-            version: version.to_string(),
-        };
+        let img_ref = parse_reference(&service.registry)?;
+        // the image is pushed with its tag, a digest cannot be the target of a push
+        if img_ref.digest.is_some() {
+            return Err(MirrorError::new(&format!(
+                "[package] registry {} for {} must be a tag not a digest",
+                service.registry, service.name
+            )));
+        }
         let impl_t = ImplTokenInterface {};
         let impl_u = ImplUploadImageInterface {};
         let local_token = get_token(
             impl_t,
-            img_ref.registry.clone(),
-            format!("{}/{}", img_ref.namespace.clone(), img_ref.name.clone()),
+            img_ref.api_host(),
+            img_ref.repository.clone(),
             !skip_tls_verify,
         )
         .await?;
 
+        let repo_url = repository_url(&img_ref.api_host(), &img_ref.repository, *skip_tls_verify);
         let paths = fs::read_dir(format!(
             "{}/generated/{}/blobs/sha256/",
             working_dir, service.name
         ))
         .unwrap();
+        // every blob is attempted, failures are reported together
+        let mut failures = vec![];
         for path in paths {
-            let digest = path
-                .unwrap()
-                .path()
-                .file_name()
-                .unwrap()
-                .to_string_lossy()
-                .to_string();
-            let req_blobs = impl_u
-                .process_blob(
-                    img_ref.registry.clone(),
-                    format!("{}/{}", img_ref.namespace, img_ref.name),
-                    format!("{}/generated/{}/blobs/sha256/", working_dir, service.name),
-                    true,
-                    digest.clone(),
+            let path = path.unwrap().path();
+            let digest = format!("sha256:{}", path.file_name().unwrap().to_string_lossy());
+            let size = fs::metadata(&path).map(|m| m.len()).ok();
+            let res = push_blob(&repo_url, &local_token, &path.to_string_lossy(), &digest).await;
+            match res {
+                Ok(BlobPush::Exists) => {
+                    debug!("blob {} already exists", digest);
+                    progress
+                        .report(&service.name, &format!("blob {} exists", digest), None)
+                        .await;
+                }
+                Ok(BlobPush::Pushed) => {
+                    progress
+                        .report(&service.name, &format!("pushed blob {}", digest), size)
+                        .await;
+                }
+                Err(err) => {
+                    error!("{}", err.to_string().to_lowercase());
+                    failures.push(format!("{} {}", digest, err.to_string().to_lowercase()));
+                }
+            }
+        }
+        if !failures.is_empty() {
+            console_icon_err();
+            return Err(MirrorError::new(&format!(
+                "[package] {} failed to push {} blob(s): {}",
+                service.name,
+                failures.len(),
+                failures.join(", ")
+            )));
+        }
+        if index.manifests.len() > 1 {
+            let res = push_index(
+                &img_ref,
+                &index,
+                &format!("{}/generated/{}", working_dir, service.name),
+                *skip_tls_verify,
+                &local_token,
+            )
+            .await;
+            if res.is_err() {
+                console_icon_err();
+                return Err(MirrorError::new(&format!(
+                    "[package] pushing index {}",
+                    res.err().unwrap().to_string().to_lowercase()
+                )));
+            }
+        } else {
+            let digest = index.manifests[0].digest.clone();
+            // read the manifest
+            let mnfst = fs_handler(
+                format!(
+                    "{}/generated/{}/blobs/sha256/{}",
+                    working_dir,
+                    service.name,
+                    digest.split(":").nth(1).unwrap()
+                ),
+                "read",
+                None,
+            )
+            .await?;
+            let req_mfst = impl_u
+                .process_manifest_string(
+                    img_ref.api_host(),
+                    img_ref.repository.clone(),
+                    mnfst.clone(),
+                    ManifestType::Oci,
+                    img_ref.version(),
                     local_token.clone(),
                 )
                 .await;
-            if req_blobs.is_err() {
+            if req_mfst.is_err() {
                 console_icon_err();
-                error!(
-                    "{}",
-                    req_blobs.err().as_ref().unwrap().to_string().to_lowercase()
-                );
-                //process::exit(1);
+                return Err(MirrorError::new(&format!(
+                    "[package] pushing manifest {}",
+                    req_mfst.err().unwrap().to_string().to_lowercase()
+                )));
             }
         }
-        let data = fs_handler(
-            format!("{}/generated/{}/index.json", working_dir, service.name),
+        // referrers are pushed once the manifests they refer to exist
+        for (subject, referrer) in referrers.iter() {
+            let manifest =
+                fs_handler(blob_path(&layout_dir, &referrer.digest)?, "read", None).await?;
+            let res = push_referrer(&repo_url, &local_token, subject, referrer, manifest).await;
+            if res.is_err() {
+                console_icon_err();
+                return Err(MirrorError::new(&format!(
+                    "[package] pushing signature referrer {}",
+                    res.err().unwrap().to_string().to_lowercase()
+                )));
+            }
+            progress
+                .report(
+                    &service.name,
+                    &format!("pushed signature {}", referrer.digest),
+                    None,
+                )
+                .await;
+        }
+        progress.report(&service.name, "packaged", None).await;
+        console_icon_ok();
+    }
+    Ok(())
+}
+
+// push the manifest of each platform by digest, then the image index with the tag
+async fn push_index(
+    img_ref: &Reference,
+    index: &OCIIndex,
+    layout_dir: &str,
+    skip_tls_verify: bool,
+    token: &str,
+) -> Result<(), MirrorError> {
+    let url = repository_url(&img_ref.api_host(), &img_ref.repository, skip_tls_verify);
+    for entry in index.manifests.iter() {
+        let hash = entry.digest.split(":").nth(1).unwrap_or_default();
+        let manifest = fs_handler(
+            format!("{}/blobs/sha256/{}", layout_dir, hash),
             "read",
             None,
         )
         .await?;
-        let res_index = serde_json::from_str(&data);
-        if res_index.is_err() {
-            console_icon_err();
-            error!(
-                "parsing index.json {}",
-                res_index.as_ref().err().unwrap().to_string().to_lowercase()
-            );
-            //process::exit(1);
-        }
-        let index: OCIIndex = res_index.unwrap();
-        let digest = index.manifests[0].digest.clone();
-        // read the manifest
-        let mnfst = fs_handler(
-            format!(
-                "{}/generated/{}/blobs/sha256/{}",
-                working_dir,
-                service.name,
-                digest.split(":").nth(1).unwrap()
-            ),
-            "read",
-            None,
+        put_manifest(
+            &format!("{}/manifests/{}", url, entry.digest),
+            token,
+            &entry.media_type,
+            manifest,
         )
         .await?;
-        let req_mfst = impl_u
-            .process_manifest_string(
-                img_ref.registry.clone(),
-                format!("{}/{}", img_ref.namespace, img_ref.name),
-                mnfst.clone(),
-                ManifestType::Oci,
-                img_ref.version,
-                local_token.clone(),
-            )
-            .await;
-        if req_mfst.is_err() {
-            console_icon_err();
-            error!(
-                "{}",
-                req_mfst.err().as_ref().unwrap().to_string().to_lowercase()
-            );
-            //process::exit(1);
-        }
-        console_icon_ok();
     }
+    let index_json = serde_json::to_string(index).unwrap();
+    put_manifest(
+        &format!("{}/manifests/{}", url, img_ref.version()),
+        token,
+        OCI_INDEX,
+        index_json,
+    )
+    .await?;
     Ok(())
 }
 
+// pick the manifest for a platform from an image index,
+// an index with a single image without platform is used as is
+fn select_manifest(index: &OCIIndex, platform: &ManifestPlatform) -> Option<Layer> {
+    // referrers listed in a layout index are not images
+    let images = index
+        .manifests
+        .iter()
+        .filter(|entry| entry.artifact_type.is_none())
+        .cloned()
+        .collect::<Vec<Layer>>();
+    let selected = images
+        .iter()
+        .find(|entry| entry.platform.as_ref() == Some(platform));
+    match (selected, images.as_slice()) {
+        (Some(entry), _) => Some(entry.clone()),
+        (None, [entry]) if entry.platform.is_none() => Some(entry.clone()),
+        _ => None,
+    }
+}
+
 pub async fn stage(
     from_registry: bool,
     working_dir: String,
     config_file: String,
     skip_tls_verify: bool,
-) -> Result<(), MirrorError> {
+    digests: &HashMap<String, String>,
+    trust_policy: &TrustStore,
+    allow_unsigned: bool,
+    progress: &ProgressReporter,
+) -> Result<Vec<DeployedService>, MirrorError> {
     trace!("from-registry {}", from_registry);
     let config = load_config(config_file.to_string()).await?;
     let sc = parse_yaml_config(config)?;
     debug!("working-dir {}", working_dir);
     debug!("microservices struct {:#?}", sc);
+    let mut staged = vec![];
+    // multi-architecture images are staged with the manifest matching this node
+    let platform = ManifestPlatform {
+        architecture: oci_architecture(),
+        os: "linux".to_string(),
+    };
     for service in sc.spec.services.iter() {
+        progress.check_cancelled()?;
+        let manifest_digest: Option<String>;
         let staging_dir = format!("{}/staging/{}", working_dir, service.name.clone());
         fs_handler(staging_dir.clone(), "create_dir", None).await?;
         let ms_dir = format!("{}/microservices/{}", working_dir, service.name.clone());
-        fs_handler(ms_dir.clone(), "create_dir", None).await?;
+        // layers are unpacked in the staging dir and only installed once the signature is verified
+        let rootfs = format!("{}/rootfs", staging_dir);
+        let _ = fs::remove_dir_all(&rootfs);
+        fs_handler(rootfs.clone(), "create_dir", None).await?;
+        let signature: Option<SignatureFile>;
         if !from_registry {
             info!("staging for service (from tar.gz) {}", service.name.clone());
+            progress
+                .report(&service.name, "unpacking artifacts", None)
+                .await;
             let data = std::fs::File::open(format!(
                 "{}/artifacts/{}.pkg",
                 working_dir,
@@ -206,52 +388,113 @@ pub async fn stage(
             let res = archive.unpack(staging_dir.clone());
             if res.is_err() {
                 console_icon_err();
-                error!(
+                return Err(MirrorError::new(&format!(
                     "[staging] untar service package {}",
                     res.as_ref().err().unwrap().to_string().to_ascii_lowercase()
-                );
-                process::exit(1);
+                )));
+            }
+            let data = fs_handler(format!("{}/index.json", staging_dir), "read", None).await?;
+            let res_index = serde_json::from_str::<OCIIndex>(&data);
+            if res_index.is_err() {
+                return Err(MirrorError::new(&format!(
+                    "[staging] parsing index.json {}",
+                    res_index.err().unwrap().to_string().to_lowercase()
+                )));
+            }
+            let layout_index = res_index.unwrap();
+            let entry = select_manifest(&layout_index, &platform);
+            if entry.is_none() {
+                return Err(MirrorError::new(&format!(
+                    "[staging] no manifest for platform {}/{} in {}",
+                    platform.os, platform.architecture, service.name
+                )));
             }
+            let entry_digest = entry.unwrap().digest;
+            let data = fs_handler(blob_path(&staging_dir, &entry_digest)?, "read", None).await?;
+            verify_manifest(&data, &entry_digest).map_err(|e| {
+                MirrorError::new(&format!("[staging] {}", e.to_string().to_lowercase()))
+            })?;
+            let res_manifest = serde_json::from_str::<Manifest>(&data);
+            if res_manifest.is_err() {
+                return Err(MirrorError::new(&format!(
+                    "[staging] parsing manifest {}",
+                    res_manifest.err().unwrap().to_string().to_lowercase()
+                )));
+            }
+            let layers = res_manifest.unwrap().layers.unwrap_or_default();
+            if layers.is_empty() {
+                return Err(MirrorError::new(&format!(
+                    "[staging] manifest {} has no layers",
+                    entry_digest
+                )));
+            }
+            // layers are unpacked in order, later layers overwrite earlier files
+            for layer in layers.iter() {
+                progress.check_cancelled()?;
+                let blob_file = blob_path(&staging_dir, &layer.digest)?;
+                verify_blob(&blob_file, layer).map_err(|e| {
+                    MirrorError::new(&format!("[staging] {}", e.to_string().to_lowercase()))
+                })?;
+                unpack_layer(&blob_file, &layer.media_type, &rootfs)?;
+            }
+            signature = layout_signature(&staging_dir, &layout_index, &entry_digest).await?;
+            manifest_digest = Some(entry_digest);
         } else {
             info!(
                 "staging for service (from registry) {}",
                 service.name.clone()
             );
+            progress
+                .report(&service.name, "pulling manifest", None)
+                .await;
             // pull artifacts from registry
-            let parts = service.registry.split("/").collect::<Vec<&str>>();
-            let (name, version) = parts[3].split_once(":").unwrap();
-
-            let img_ref = ImageReference {
-                registry: parts[0].to_string(),
-                namespace: format!("{}/{}", parts[1], parts[2]),
-                name: name.to_string(),
-                version: version.to_string(),
-            };
+            let img_ref = parse_reference(&service.registry)?;
             let impl_t = ImplTokenInterface {};
             let local_token = get_token(
                 impl_t,
-                img_ref.registry.clone(),
-                format!("{}/{}", img_ref.namespace.clone(), img_ref.name.clone()),
+                img_ref.api_host(),
+                img_ref.repository.clone(),
                 !skip_tls_verify,
             )
             .await?;
 
-            let impl_d = ImplDownloadImageInterface {};
-
-            let manifest_url = match skip_tls_verify {
-                true => format!(
-                    "http://{}/v2/{}/{}/manifests/{}",
-                    img_ref.registry, img_ref.namespace, img_ref.name, img_ref.version
-                ),
-                false => format!(
-                    "https://{}/v2/{}/{}/manifests/{}",
-                    img_ref.registry, img_ref.namespace, img_ref.name, img_ref.version
-                ),
-            };
-
-            let manifest = impl_d
-                .get_manifest(manifest_url.clone(), local_token.clone())
-                .await?;
+            let repo_url =
+                repository_url(&img_ref.api_host(), &img_ref.repository, skip_tls_verify);
+            // the digest is resolved once before the command is sent, every node pulls the same manifest
+            let pinned = digests
+                .get(&service.name)
+                .cloned()
+                .or(img_ref.digest.clone());
+            if pinned.is_none() {
+                return Err(MirrorError::new(&format!(
+                    "[staging] no digest resolved for {}",
+                    service.name
+                )));
+            }
+            let top_digest = pinned.unwrap();
+            info!("pulling {} pinned to {}", service.name, top_digest);
+            let manifest_url = format!("{}/manifests/{}", repo_url, top_digest);
+            let mut manifest = get_manifest(&manifest_url, &local_token).await?;
+            verify_manifest(&manifest, &top_digest).map_err(|e| {
+                MirrorError::new(&format!("[staging] {}", e.to_string().to_lowercase()))
+            })?;
+            // the tag points to an image index, pull the manifest for this platform
+            if let Ok(index) = serde_json::from_str::<OCIIndex>(&manifest) {
+                let entry = select_manifest(&index, &platform);
+                if entry.is_none() {
+                    return Err(MirrorError::new(&format!(
+                        "[staging] no manifest for platform {}/{} in {}",
+                        platform.os, platform.architecture, service.registry
+                    )));
+                }
+                let entry_digest = entry.unwrap().digest;
+                let url = format!("{}/manifests/{}", repo_url, entry_digest);
+                manifest = get_manifest(&url, &local_token).await?;
+                verify_manifest(&manifest, &entry_digest).map_err(|e| {
+                    MirrorError::new(&format!("[staging] {}", e.to_string().to_lowercase()))
+                })?;
+            }
+            manifest_digest = Some(format!("sha256:{}", digest(&manifest)));
 
             fs_handler(
                 format!("{}/index.json", staging_dir.clone()),
@@ -264,7 +507,7 @@ pub async fn stage(
             trace!("index.json {}", manifest);
             if res_json.is_err() {
                 console_icon_err();
-                error!(
+                return Err(MirrorError::new(&format!(
                     "[staging] parsing index.json {}",
                     res_json
                         .as_ref()
@@ -272,71 +515,302 @@ pub async fn stage(
                         .unwrap()
                         .to_string()
                         .to_ascii_lowercase()
-                );
-                process::exit(1);
+                )));
             }
             let oci_index: Manifest = res_json.unwrap();
-            let blob_sum_sha = oci_index.layers.unwrap()[0].digest.clone();
-            let blob_sum = blob_sum_sha.split(":").nth(1).unwrap();
-            let blobs_dir = format!("{}/blobs/sha256/", staging_dir);
-            let blob_url = match skip_tls_verify {
-                true => format!(
-                    "http://{}/v2/{}/{}/blobs/",
-                    img_ref.registry, img_ref.namespace, img_ref.name
-                ),
-                false => format!(
-                    "https://{}/v2/{}/{}/blobs/",
-                    img_ref.registry, img_ref.namespace, img_ref.name
-                ),
-            };
+            fs_handler(format!("{}/blobs/sha256", staging_dir), "create_dir", None).await?;
+            // layers are unpacked in order, later layers overwrite earlier files
+            for layer in oci_index.layers.unwrap_or_default().iter() {
+                progress.check_cancelled()?;
+                let blob_file = blob_path(&staging_dir, &layer.digest)?;
+                let url = format!("{}/blobs/{}", repo_url, layer.digest);
+                let size =
+                    get_blob(&url, &local_token, &blob_file, progress, &service.name).await?;
+                // nothing is unpacked from a blob that does not match the manifest
+                verify_blob(&blob_file, layer).map_err(|e| {
+                    MirrorError::new(&format!("[staging] {}", e.to_string().to_lowercase()))
+                })?;
+                progress
+                    .report(
+                        &service.name,
+                        &format!("downloaded blob {}", layer.digest),
+                        Some(size),
+                    )
+                    .await;
+                unpack_layer(&blob_file, &layer.media_type, &rootfs)?;
+            }
+            // signatures refer to the platform manifest (or to the image index)
+            let mut subjects = vec![manifest_digest.clone().unwrap()];
+            if top_digest != subjects[0] {
+                subjects.push(top_digest);
+            }
+            signature = fetch_signature(&repo_url, &local_token, &staging_dir, &subjects).await?;
+        }
+        verify_service(
+            service,
+            &rootfs,
+            manifest_digest.as_deref().unwrap_or_default(),
+            signature,
+            trust_policy,
+            allow_unsigned,
+            progress,
+        )
+        .await?;
+        let _ = fs::remove_dir_all(&ms_dir);
+        fs_handler(format!("{}/microservices", working_dir), "create_dir", None).await?;
+        let res = fs::rename(&rootfs, &ms_dir);
+        if res.is_err() {
+            return Err(MirrorError::new(&format!(
+                "[staging] installing {} {}",
+                service.name,
+                res.err().unwrap().to_string().to_lowercase()
+            )));
+        }
+        staged.push(DeployedService {
+            node: gethostname().to_string_lossy().to_string(),
+            name: service.name.clone(),
+            version: Some(service.version.clone()),
+            digest: manifest_digest,
+            status: "staged".to_string(),
+            updated: 0,
+        });
+        progress.report(&service.name, "staged", None).await;
+        console_icon_ok();
+    }
+    Ok(staged)
+}
 
-            impl_d
-                .get_blob(
-                    blobs_dir.clone(),
-                    blob_url,
-                    local_token,
-                    false,
-                    blob_sum_sha.to_string(),
+// resolve the manifest digest of each service once (before stage is sent to the workers),
+// digests pinned in microservices.lock are used unless update is set
+pub async fn resolve_digests(
+    config_file: &str,
+    skip_tls_verify: bool,
+    update: bool,
+) -> Result<HashMap<String, String>, MirrorError> {
+    let config = load_config(config_file.to_string()).await?;
+    let sc = parse_yaml_config(config)?;
+    let mut lock = load_lock(config_file).await?;
+    let mut digests = HashMap::new();
+    for service in sc.spec.services.iter() {
+        let img_ref = parse_reference(&service.registry)?;
+        let pinned = match (img_ref.digest.clone(), update) {
+            (Some(digest), _) => Some(digest),
+            (None, false) => lock.digest(&service.name, &service.registry),
+            (None, true) => None,
+        };
+        let resolved = match pinned {
+            Some(pinned) => pinned,
+            None => {
+                let impl_t = ImplTokenInterface {};
+                let local_token = get_token(
+                    impl_t,
+                    img_ref.api_host(),
+                    img_ref.repository.clone(),
+                    !skip_tls_verify,
                 )
                 .await?;
+                let repo_url =
+                    repository_url(&img_ref.api_host(), &img_ref.repository, skip_tls_verify);
+                let manifest_url = format!("{}/manifests/{}", repo_url, img_ref.version());
+                let manifest = get_manifest(&manifest_url, &local_token).await?;
+                let resolved = format!("sha256:{}", digest(&manifest));
+                info!("resolved {} to {}", service.registry, resolved);
+                resolved
+            }
+        };
+        lock.pin(&service.name, &service.registry, &resolved);
+        digests.insert(service.name.clone(), resolved);
+    }
+    save_lock(config_file, &lock).await?;
+    Ok(digests)
+}
 
-            let blob_file = format!(
-                "{}/blobs/sha256/{}/{}",
-                staging_dir,
-                &blob_sum[..2],
-                blob_sum
-            );
-            let tar_gz = File::open(blob_file);
-            let tar = GzDecoder::new(tar_gz.unwrap());
-            let mut archive = Archive::new(tar);
-            let res_untar = archive.unpack(ms_dir);
-            if res_untar.is_err() {
-                console_icon_err();
-                error!(
-                    "[staging] untar service binary {}",
-                    res_untar
-                        .as_ref()
-                        .err()
-                        .unwrap()
-                        .to_string()
-                        .to_ascii_lowercase()
-                );
-                process::exit(1);
+// signature referrer of a manifest listed in an unpacked layout (.pkg)
+async fn layout_signature(
+    layout_dir: &str,
+    index: &OCIIndex,
+    subject: &str,
+) -> Result<Option<SignatureFile>, MirrorError> {
+    let referrers = index
+        .manifests
+        .iter()
+        .filter(|entry| entry.artifact_type.as_deref() == Some(SIGNATURE_ARTIFACT_TYPE));
+    for referrer in referrers {
+        let data = fs_handler(blob_path(layout_dir, &referrer.digest)?, "read", None).await?;
+        verify_manifest(&data, &referrer.digest)?;
+        let manifest = serde_json::from_str::<Manifest>(&data);
+        if manifest.is_err() {
+            continue;
+        }
+        let manifest = manifest.unwrap();
+        if manifest.subject.map(|subject| subject.digest).as_deref() != Some(subject) {
+            continue;
+        }
+        let layers = manifest.layers.unwrap_or_default();
+        if layers.is_empty() {
+            continue;
+        }
+        let path = blob_path(layout_dir, &layers[0].digest)?;
+        verify_blob(&path, &layers[0])?;
+        return Ok(Some(read_signature_blob(&path).await?));
+    }
+    Ok(None)
+}
+
+// signature blob of a referrer (SignatureJson)
+async fn read_signature_blob(path: &str) -> Result<SignatureFile, MirrorError> {
+    let data = fs_handler(path.to_string(), "read", None).await?;
+    let sig_json = serde_json::from_str::<SignatureJson>(&data);
+    if sig_json.is_err() {
+        return Err(MirrorError::new(&format!(
+            "[staging] parsing signature {}",
+            sig_json.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    let sig_json = sig_json.unwrap();
+    Ok(SignatureFile {
+        algorithm: sig_json.algorithm,
+        payload: sig_json.payload,
+        signature: sig_json.signature,
+    })
+}
+
+// look up the signature referrer of the first subject that has one
+async fn fetch_signature(
+    repo_url: &str,
+    token: &str,
+    staging_dir: &str,
+    subjects: &[String],
+) -> Result<Option<SignatureFile>, MirrorError> {
+    for subject in subjects.iter() {
+        for referrer in find_referrers(repo_url, token, subject).await? {
+            let url = format!("{}/manifests/{}", repo_url, referrer.digest);
+            let manifest = get_manifest(&url, token).await?;
+            let manifest = serde_json::from_str::<Manifest>(&manifest);
+            if manifest.is_err() {
+                continue;
+            }
+            let manifest = manifest.unwrap();
+            if manifest.artifact_type.as_deref() != Some(SIGNATURE_ARTIFACT_TYPE) {
+                continue;
+            }
+            let layers = manifest.layers.unwrap_or_default();
+            if layers.is_empty() {
+                continue;
             }
+            let path = format!("{}/signature.json", staging_dir);
+            let url = format!("{}/blobs/{}", repo_url, layers[0].digest);
+            get_blob(&url, token, &path, &ProgressReporter::disabled(), "").await?;
+            verify_blob(&path, &layers[0])?;
+            return Ok(Some(read_signature_blob(&path).await?));
         }
-        console_icon_ok();
     }
-    Ok(())
+    Ok(None)
 }
 
-pub async fn list() -> Result<String, MirrorError> {
-    let node_info = format!(
-        "list nodes -> {}:{}",
-        gethostname().to_string_lossy().to_string(),
-        local_ip().unwrap()
-    );
-    trace!("node info {} ", node_info);
-    Ok(node_info)
+// the trust policy decides which keys are trusted for a service and whether it must be signed,
+// the signed claims must name this service, version and manifest,
+// unsigned services are refused unless explicitly allowed, a signature that does not verify is always refused
+async fn verify_service(
+    service: &Service,
+    rootfs: &str,
+    manifest_digest: &str,
+    signature: Option<SignatureFile>,
+    trust_policy: &TrustStore,
+    allow_unsigned: bool,
+    progress: &ProgressReporter,
+) -> Result<(), MirrorError> {
+    let name = &service.name;
+    let binary = format!("{}/{}", rootfs, name);
+    let verification = trust_policy.evaluate(name, &service.registry, &binary, signature.as_ref());
+    let failure = match verification {
+        Verification::Trusted(key) => match verify_claims(
+            signature.as_ref().unwrap(),
+            name,
+            &service.version,
+            manifest_digest,
+        ) {
+            Ok(()) => {
+                info!("[staging] signature verified for {} with key {}", name, key);
+                progress
+                    .report(name, &format!("signature verified (key {})", key), None)
+                    .await;
+                return Ok(());
+            }
+            Err(err) => err.to_string().to_lowercase(),
+        },
+        Verification::Unsigned => {
+            info!(
+                "[staging] {} is not signed, not required by the trust policy",
+                name
+            );
+            return Ok(());
+        }
+        Verification::Missing if allow_unsigned => {
+            warn!("[staging] {} is not signed, installing anyway", name);
+            return Ok(());
+        }
+        Verification::Missing => "no signature found".to_string(),
+        Verification::Untrusted(reason) => reason,
+    };
+    progress
+        .event(
+            EventKind::SignatureVerificationFailed,
+            name,
+            Some(failure.clone()),
+        )
+        .await;
+    console_icon_err();
+    Err(MirrorError::new(&format!(
+        "[staging] refusing to install {} {}",
+        name, failure
+    )))
+}
+
+// untar a layer blob onto the microservice directory, the decoder is
+// selected from the layer media type (plain tar, tar+gzip or tar+zstd)
+fn unpack_layer(blob_file: &str, media_type: &str, ms_dir: &str) -> Result<(), MirrorError> {
+    let tar_file = File::open(blob_file);
+    if tar_file.is_err() {
+        return Err(MirrorError::new(&format!(
+            "[staging] opening layer {}",
+            tar_file.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    let tar_file = tar_file.unwrap();
+    let reader: Box<dyn Read> = match media_type {
+        OCI_LAYER_TAR => Box::new(tar_file),
+        OCI_LAYER_ZSTD => {
+            let decoder = zstd::stream::read::Decoder::new(tar_file);
+            if decoder.is_err() {
+                return Err(MirrorError::new(&format!(
+                    "[staging] reading zstd layer {}",
+                    decoder.err().unwrap().to_string().to_lowercase()
+                )));
+            }
+            Box::new(decoder.unwrap())
+        }
+        OCI_LAYER_GZIP | "application/vnd.docker.image.rootfs.diff.tar.gzip" => {
+            Box::new(GzDecoder::new(tar_file))
+        }
+        _ => {
+            return Err(MirrorError::new(&format!(
+                "[staging] unsupported layer media type {}",
+                media_type
+            )));
+        }
+    };
+    let mut archive = Archive::new(reader);
+    archive.set_overwrite(true);
+    let res_untar = archive.unpack(ms_dir);
+    if res_untar.is_err() {
+        console_icon_err();
+        return Err(MirrorError::new(&format!(
+            "[staging] untar layer {}",
+            res_untar.err().unwrap().to_string().to_lowercase()
+        )));
+    }
+    Ok(())
 }
 
 pub async fn start(
@@ -0,0 +1,244 @@
+use crate::api::schema::*;
+use crate::config::read::*;
+use crate::job::progress::ProgressReporter;
+use crate::oci::layout::*;
+use crate::oci::reference::parse_reference;
+use crate::oci::registry::{
+    find_referrers, get_blob, get_manifest, push_blob, push_referrer, put_manifest, repository_url,
+    BlobPush, OCI_INDEX,
+};
+use custom_logger::*;
+use mirror_auth::{get_token, ImplTokenInterface};
+use mirror_error::MirrorError;
+use mirror_utils::fs_handler;
+use std::fs;
+use std::path::Path;
+
+// services from the config, or only the one requested
+fn selected_services(
+    config: &MicroserviceConfig,
+    service: &Option<String>,
+) -> Result<Vec<Service>, MirrorError> {
+    let services = config
+        .spec
+        .services
+        .iter()
+        .filter(|svc| service.as_ref().map_or(true, |name| &svc.name == name))
+        .cloned()
+        .collect::<Vec<Service>>();
+    if services.is_empty() {
+        return Err(MirrorError::new(&format!(
+            "no service {} in config",
+            service.clone().unwrap_or_default()
+        )));
+    }
+    Ok(services)
+}
+
+// pull the manifests and blobs referenced from a manifest (or index) into the layout,
+// returns the digests of the manifests pulled
+async fn pull_tree(
+    repo_url: &str,
+    token: &str,
+    layout_dir: &str,
+    manifest: &str,
+) -> Result<Vec<String>, MirrorError> {
+    let mut pending = vec![manifest.to_string()];
+    let mut pulled = vec![];
+    while let Some(current) = pending.pop() {
+        let (manifests, blobs) = references(&current)?;
+        for entry in manifests {
+            let url = format!("{}/manifests/{}", repo_url, entry.digest);
+            let child = get_manifest(&url, token).await?;
+            verify_manifest(&child, &entry.digest)?;
+            store_manifest(layout_dir, &child).await?;
+            pulled.push(entry.digest);
+            pending.push(child);
+        }
+        for blob in blobs {
+            let path = blob_path(layout_dir, &blob.digest)?;
+            if Path::new(&path).exists() {
+                continue;
+            }
+            let url = format!("{}/blobs/{}", repo_url, blob.digest);
+            let size = get_blob(&url, token, &path, &ProgressReporter::disabled(), "").await?;
+            verify_blob(&path, &blob)?;
+            debug!("pulled blob {} ({} bytes)", blob.digest, size);
+        }
+    }
+    Ok(pulled)
+}
+
+// push the blobs and child manifests referenced from a manifest in the layout,
+// children are pushed before the manifests that reference them
+async fn push_tree(
+    repo_url: &str,
+    token: &str,
+    layout_dir: &str,
+    entry: &Layer,
+) -> Result<String, MirrorError> {
+    let top = fs_handler(blob_path(layout_dir, &entry.digest)?, "read", None).await?;
+    let mut pending = vec![top.clone()];
+    let mut children = vec![];
+    while let Some(current) = pending.pop() {
+        let (manifests, blobs) = references(&current)?;
+        for blob in blobs {
+            let path = blob_path(layout_dir, &blob.digest)?;
+            if push_blob(repo_url, token, &path, &blob.digest).await? == BlobPush::Exists {
+                debug!("blob {} already exists", blob.digest);
+            }
+        }
+        for child in manifests {
+            let manifest = fs_handler(blob_path(layout_dir, &child.digest)?, "read", None).await?;
+            pending.push(manifest.clone());
+            children.push((child, manifest));
+        }
+    }
+    for (child, manifest) in children.into_iter().rev() {
+        let url = format!("{}/manifests/{}", repo_url, child.digest);
+        put_manifest(&url, token, &child.media_type, manifest).await?;
+    }
+    Ok(top)
+}
+
+// copy the services (and the signature referrers) from the registry to an oci layout archive
+pub async fn export(
+    config_file: &str,
+    working_dir: &str,
+    output: &str,
+    service: &Option<String>,
+    skip_tls_verify: bool,
+) -> Result<(), MirrorError> {
+    let config = load_config(config_file.to_string()).await?;
+    let sc = parse_yaml_config(config)?;
+    let layout_dir = format!("{}/layout", working_dir);
+    let _ = fs::remove_dir_all(&layout_dir);
+    fs_handler(format!("{}/blobs/sha256", layout_dir), "create_dir", None).await?;
+    let mut entries = vec![];
+    for svc in selected_services(&sc, service)?.iter() {
+        info!("exporting {}", svc.registry);
+        let img_ref = parse_reference(&svc.registry)?;
+        let token = get_token(
+            ImplTokenInterface {},
+            img_ref.api_host(),
+            img_ref.repository.clone(),
+            !skip_tls_verify,
+        )
+        .await?;
+        let repo_url = repository_url(&img_ref.api_host(), &img_ref.repository, skip_tls_verify);
+        let url = format!("{}/manifests/{}", repo_url, img_ref.version());
+        let manifest = get_manifest(&url, &token).await?;
+        let mut descriptor = store_manifest(&layout_dir, &manifest).await?;
+        let mut subjects = vec![descriptor.digest.clone()];
+        subjects.extend(pull_tree(&repo_url, &token, &layout_dir, &manifest).await?);
+        descriptor.annotations = Some(Annotations {
+            image_title: Some(svc.name.clone()),
+            image_created: None,
+            ref_name: Some(svc.registry.clone()),
+        });
+        entries.push(descriptor);
+        // signatures are referrers of the image (or of a platform manifest)
+        for subject in subjects.iter() {
+            for referrer in find_referrers(&repo_url, &token, subject).await? {
+                let url = format!("{}/manifests/{}", repo_url, referrer.digest);
+                let manifest = get_manifest(&url, &token).await?;
+                let mut descriptor = store_manifest(&layout_dir, &manifest).await?;
+                pull_tree(&repo_url, &token, &layout_dir, &manifest).await?;
+                descriptor.annotations = Some(Annotations {
+                    image_title: Some(svc.name.clone()),
+                    image_created: None,
+                    ref_name: None,
+                });
+                entries.push(descriptor);
+            }
+        }
+    }
+    let index = OCIIndex {
+        schema_version: 2,
+        media_type: Some(OCI_INDEX.to_string()),
+        manifests: entries,
+    };
+    fs_handler(
+        format!("{}/index.json", layout_dir),
+        "write",
+        Some(serde_json::to_string(&index).unwrap()),
+    )
+    .await?;
+    write_layout_marker(&layout_dir).await?;
+    archive_layout(&layout_dir, output)?;
+    info!("exported to {}", output);
+    Ok(())
+}
+
+// push the services (and the signature referrers) from an oci layout archive,
+// images are tagged with the registry reference of the service in the config
+pub async fn import(
+    config_file: &str,
+    working_dir: &str,
+    input: &str,
+    service: &Option<String>,
+    skip_tls_verify: bool,
+) -> Result<(), MirrorError> {
+    let config = load_config(config_file.to_string()).await?;
+    let sc = parse_yaml_config(config)?;
+    let layout_dir = format!("{}/layout-import", working_dir);
+    let _ = fs::remove_dir_all(&layout_dir);
+    fs_handler(layout_dir.clone(), "create_dir", None).await?;
+    unpack_layout(input, &layout_dir)?;
+    let index = read_index(&layout_dir).await?;
+    for svc in selected_services(&sc, service)?.iter() {
+        let mut entries = index
+            .manifests
+            .iter()
+            .filter(|entry| {
+                entry
+                    .annotations
+                    .as_ref()
+                    .and_then(|a| a.image_title.as_ref())
+                    == Some(&svc.name)
+            })
+            .cloned()
+            .collect::<Vec<Layer>>();
+        if entries.is_empty() {
+            return Err(MirrorError::new(&format!(
+                "no image for service {} in {}",
+                svc.name, input
+            )));
+        }
+        // the image first (tagged), then its referrers (by digest)
+        entries.sort_by_key(|entry| entry.annotations.as_ref().unwrap().ref_name.is_none());
+        info!("importing {}", svc.registry);
+        let img_ref = parse_reference(&svc.registry)?;
+        let token = get_token(
+            ImplTokenInterface {},
+            img_ref.api_host(),
+            img_ref.repository.clone(),
+            !skip_tls_verify,
+        )
+        .await?;
+        let repo_url = repository_url(&img_ref.api_host(), &img_ref.repository, skip_tls_verify);
+        for entry in entries.iter() {
+            let manifest = push_tree(&repo_url, &token, &layout_dir, entry).await?;
+            if entry.annotations.as_ref().unwrap().ref_name.is_some() {
+                let url = format!("{}/manifests/{}", repo_url, img_ref.version());
+                put_manifest(&url, &token, &entry.media_type, manifest).await?;
+                continue;
+            }
+            // referrers also go to the referrers tag when the registry has no referrers api
+            let subject = serde_json::from_str::<Manifest>(&manifest)
+                .ok()
+                .and_then(|m| m.subject);
+            match subject {
+                Some(subject) => {
+                    push_referrer(&repo_url, &token, &subject.digest, entry, manifest).await?
+                }
+                None => {
+                    let url = format!("{}/manifests/{}", repo_url, entry.digest);
+                    put_manifest(&url, &token, &entry.media_type, manifest).await?;
+                }
+            }
+        }
+    }
+    info!("imported from {}", input);
+    Ok(())
+}